@@ -1,3 +1,7 @@
+#![forbid(unsafe_code)]
+
+#[cfg(feature = "index")]
 pub mod list_person_all_extended_csv;
+pub mod prelude;
 pub mod ruby_txt;
 pub mod utility;
@@ -1,11 +1,17 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    fmt,
+    io::Read,
+    path::{Component, Path, PathBuf},
+};
 
 use anyhow::{bail, ensure, Context, Result};
 use serde::Serialize;
 
-use crate::utility::date::Date;
+use crate::utility::{date::Date, str::decode_shift_jis};
 
-#[derive(Debug, PartialEq, Eq, Serialize)]
+#[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Author {
     pub id: usize,                   // 人物 ID
@@ -24,6 +30,79 @@ pub struct Author {
     pub copyright: bool, // 人物著作権フラグ
 }
 
+impl Author {
+    // CSV の各レコードで全項目が一致しているか
+    fn is_same_record(&self, other: &Self) -> bool {
+        self.id == other.id
+            && self.last_name == other.last_name
+            && self.first_name == other.first_name
+            && self.last_name_kana == other.last_name_kana
+            && self.first_name_kana == other.first_name_kana
+            && self.last_name_sort_key == other.last_name_sort_key
+            && self.first_name_sort_key == other.first_name_sort_key
+            && self.last_name_romaji == other.last_name_romaji
+            && self.first_name_romaji == other.first_name_romaji
+            && self.birth_date == other.birth_date
+            && self.death_date == other.death_date
+            && self.copyright == other.copyright
+    }
+}
+
+// 同じ人物 ID なら同じ人物とみなす
+impl PartialEq for Author {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for Author {}
+
+// 読みの五十音順 (同じ読みなら人物 ID 順)
+impl Ord for Author {
+    fn cmp(&self, other: &Self) -> Ordering {
+        if self.id == other.id {
+            return Ordering::Equal;
+        }
+
+        (&self.last_name_sort_key, &self.first_name_sort_key, self.id).cmp(&(
+            &other.last_name_sort_key,
+            &other.first_name_sort_key,
+            other.id,
+        ))
+    }
+}
+
+impl PartialOrd for Author {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// 役割フラグ
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthorRole {
+    Author,        // 著者
+    Translator,    // 翻訳者
+    Editor,        // 編者
+    Compiler,      // 編纂者
+    Proofreader,   // 校訂者
+    Other(String), // その他
+}
+
+impl From<&str> for AuthorRole {
+    fn from(role: &str) -> Self {
+        match role {
+            "著者" => Self::Author,
+            "翻訳者" | "翻訳" => Self::Translator,
+            "編者" | "編集者" => Self::Editor,
+            "編纂者" => Self::Compiler,
+            "校訂者" => Self::Proofreader,
+            role => Self::Other(role.to_owned()),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Serialize, Hash)]
 #[serde(rename_all = "camelCase")]
 pub struct BookAuthor {
@@ -73,6 +152,69 @@ pub struct Book {
     pub html_url: Option<String>, // XHTML / HTML ファイル URL
 }
 
+impl Book {
+    // この作品の最初の人物の役割
+    pub fn primary_author_role(&self, index: &AozorabunkoIndexList) -> Option<AuthorRole> {
+        index
+            .book_authors
+            .iter()
+            .find(|ba| ba.book_id == self.id)
+            .map(|ba| AuthorRole::from(ba.author_role.as_str()))
+    }
+
+    // 以下は文字遣い種別 (新字新仮名, 新字旧仮名, 旧字新仮名, 旧字旧仮名, その他) による
+    // 旧字を使っているものを古典的とみなす
+    pub fn is_classic(&self) -> bool {
+        self.uses_old_characters()
+    }
+
+    pub fn uses_old_kana(&self) -> bool {
+        self.writing_system.contains("旧仮名")
+    }
+
+    pub fn uses_new_kana(&self) -> bool {
+        self.writing_system.contains("新仮名")
+    }
+
+    pub fn uses_old_characters(&self) -> bool {
+        self.writing_system.contains("旧字")
+    }
+
+    // テキストファイル (zip) の aozorabunko リポジトリ内のパス
+    // 青空文庫の外にあるものや zip でないものは None
+    pub fn txt_zip_local_path(&self, repo_root: &Path) -> Option<PathBuf> {
+        let txt_url = self.txt_url.as_ref()?;
+
+        let relative = ["https://www.aozora.gr.jp/", "http://www.aozora.gr.jp/"]
+            .iter()
+            .find_map(|prefix| txt_url.strip_prefix(prefix))?;
+        // クエリ・フラグメントは除く
+        let relative = relative.split(['?', '#']).next().unwrap();
+
+        if !relative.to_lowercase().ends_with(".zip") {
+            return None;
+        }
+
+        // リポジトリの外を指すものは受け付けない
+        let relative = Path::new(relative);
+        if !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            return None;
+        }
+
+        Some(repo_root.join(relative))
+    }
+}
+
+// 作品とその人物 (AozorabunkoIndexList::iter_books)
+#[derive(Debug)]
+pub struct BookWithAuthors<'a> {
+    pub book: &'a Book,
+    pub authors: Vec<(&'a Author, AuthorRole)>, // 人物 ID 順
+}
+
 #[derive(Serialize)]
 pub struct AozorabunkoIndexList {
     pub authors: Vec<Author>,
@@ -80,8 +222,111 @@ pub struct AozorabunkoIndexList {
     pub book_authors: Vec<BookAuthor>,
 }
 
+impl AozorabunkoIndexList {
+    // books と authors は解析時に id 順に並べてあるので二分探索する
+    pub fn book(&self, id: usize) -> Option<&Book> {
+        let index = self.books.binary_search_by_key(&id, |b| b.id).ok()?;
+        Some(&self.books[index])
+    }
+
+    pub fn author(&self, id: usize) -> Option<&Author> {
+        let index = self.authors.binary_search_by_key(&id, |a| a.id).ok()?;
+        Some(&self.authors[index])
+    }
+
+    // book_authors の順 (人物 ID 順)
+    pub fn authors_of(&self, book_id: usize) -> Vec<&Author> {
+        self.book_authors
+            .iter()
+            .filter(|ba| ba.book_id == book_id)
+            .filter_map(|ba| self.author(ba.author_id))
+            .collect()
+    }
+
+    // 作品 ID 順に、各作品の人物をまとめて辿る
+    // books と book_authors はともに作品 ID 順に並べてあるので一度ずつ読めばよい
+    pub fn iter_books(&self) -> impl Iterator<Item = BookWithAuthors<'_>> {
+        let mut book_authors = self.book_authors.iter().peekable();
+        self.books.iter().map(move |book| {
+            while book_authors.next_if(|ba| ba.book_id < book.id).is_some() {}
+
+            let mut authors = Vec::new();
+            while let Some(ba) = book_authors.next_if(|ba| ba.book_id == book.id) {
+                if let Some(author) = self.author(ba.author_id) {
+                    authors.push((author, AuthorRole::from(ba.author_role.as_str())));
+                }
+            }
+
+            BookWithAuthors { book, authors }
+        })
+    }
+
+    pub fn statistics(&self) -> CorpusStatistics {
+        let mut writing_system_counts = HashMap::new();
+        for book in &self.books {
+            *writing_system_counts
+                .entry(book.writing_system.clone())
+                .or_insert(0) += 1;
+        }
+
+        CorpusStatistics {
+            total_books: self.books.len(),
+            books_with_copyright: self.books.iter().filter(|b| b.copyright).count(),
+            books_with_txt: self.books.iter().filter(|b| b.txt_url.is_some()).count(),
+            books_with_html: self.books.iter().filter(|b| b.html_url.is_some()).count(),
+            total_authors: self.authors.len(),
+            authors_with_copyright: self.authors.iter().filter(|a| a.copyright).count(),
+            writing_system_counts,
+        }
+    }
+}
+
+// 作品・人物の集計
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CorpusStatistics {
+    pub total_books: usize,
+    pub books_with_copyright: usize,
+    pub books_with_txt: usize,
+    pub books_with_html: usize,
+    pub total_authors: usize,
+    pub authors_with_copyright: usize,
+    pub writing_system_counts: HashMap<String, usize>, // 文字遣い種別 => 作品数
+}
+
+impl fmt::Display for CorpusStatistics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Books: {}", self.total_books)?;
+        writeln!(f, "  with copyright: {}", self.books_with_copyright)?;
+        writeln!(f, "  with txt: {}", self.books_with_txt)?;
+        writeln!(f, "  with html: {}", self.books_with_html)?;
+        writeln!(f, "Authors: {}", self.total_authors)?;
+        writeln!(f, "  with copyright: {}", self.authors_with_copyright)?;
+        write!(f, "Writing systems:")?;
+
+        let mut writing_system_counts: Vec<_> = self.writing_system_counts.iter().collect();
+        writing_system_counts.sort();
+        for (writing_system, count) in writing_system_counts {
+            write!(f, "\n  {}: {}", writing_system, count)?;
+        }
+
+        Ok(())
+    }
+}
+
 pub fn parse_list_person_all_extended_csv(csv: &str) -> Result<AozorabunkoIndexList> {
-    let mut reader = csv::Reader::from_reader(csv.as_bytes());
+    parse_index_list_from_reader(csv.as_bytes())
+}
+
+// Shift_JIS 版 (list_person_all_extended.csv)
+pub fn parse_index_list_from_shift_jis(bytes: &[u8]) -> Result<AozorabunkoIndexList> {
+    let csv = decode_shift_jis(bytes).context("Failed to decode CSV as Shift_JIS")?;
+    parse_index_list_from_reader(csv.as_bytes())
+}
+
+// 全体を String に読み込まずに 1 行ずつ解析する
+pub fn parse_index_list_from_reader<R: Read>(reader: R) -> Result<AozorabunkoIndexList> {
+    let mut reader = csv::Reader::from_reader(reader);
 
     let mut authors = HashMap::<usize, Author>::new();
     let mut books = HashMap::<usize, Book>::new();
@@ -96,7 +341,7 @@ pub fn parse_list_person_all_extended_csv(csv: &str) -> Result<AozorabunkoIndexL
 
         if let Some(existing_author) = authors.get(&author.id) {
             ensure!(
-                existing_author == &author,
+                existing_author.is_same_record(&author),
                 "Different authors has same id:\n{:?}\n{:?}",
                 &existing_author,
                 &author
@@ -1,33 +1,123 @@
 use anyhow::{bail, ensure, Context, Result};
 use indicatif::{ProgressBar, ProgressIterator, ProgressStyle};
+use once_cell::sync::Lazy;
+use serde::Serialize;
 use std::{
-    collections::HashSet,
+    collections::{BTreeMap, HashMap, HashSet},
     env,
     fs::{self, File},
-    path::PathBuf,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 
 use aozorabunko_json::{
     list_person_all_extended_csv::parser::{
-        parse_list_person_all_extended_csv, AozorabunkoIndexList,
+        parse_index_list_from_reader, parse_index_list_from_shift_jis, AozorabunkoIndexList, Book,
     },
     ruby_txt::{
-        parser::{parse_ruby_txt, ParsedRubyTxt},
-        renderer::{render_ruby_txt, RenderedRubyTxt},
+        footer_metadata::{parse_footer_metadata, FooterMetadata},
+        parser::{
+            parse_ruby_txt_with_options, ParseOptions, ParseWarning, ParsedRubyTxt,
+            ParsedRubyTxtElement,
+        },
+        renderer::{render_ruby_txt_with_options, RenderOptions, RenderedRubyTxt},
+        ruby_pair::ruby_pairs,
         tokenizer::tokenize_ruby_txt,
     },
-    utility::zip::ZipReader,
+    utility::{
+        date::Date,
+        zip::{find_txt_entry, ZipReader},
+    },
 };
 
 struct Args {
     aozorabunko_path: String,
     output_path: Option<String>,
+    sqlite_path: Option<String>, // ディレクトリの代わりに 1 つの SQLite のファイルに書き出す
+    validate: bool,              // 出力せずに全作品のパース・レンダリングの成否を集計する
+    review_blacklist: bool,      // 未対応としている作品のみを処理し、現状の成否を出力する
+    list_unsupported: bool,      // 未対応としている作品を理由とともに表示して終了する
+    layout: OutputLayout,
+    format: OutputFormat,
+    pretty: bool,             // 出力する JSON を整形する (既定では詰めて書く)
+    streaming: bool, // books.json の代わりに books.jsonl に処理した作品から 1 行ずつ書き出す
+    furigana: bool,  // 各作品の JSON の代わりにルビの (親文字, 読み) を furigana.jsonl に集める
+    author_ids: Vec<usize>, // 空でなければ、これらの人物の作品のみを処理する
+    max_books: Option<usize>, // パースまで進んだ作品がこの数に達したら終える
+    metadata_diff: bool, // CSV と .txt の末尾の底本・入力者・校正者の食い違いを metadata_diff.json に書き出す
 }
 
 fn get_args() -> Result<Args> {
     let args: Vec<String> = env::args().skip(1).collect();
 
-    let opts = getopts::Options::new();
+    let mut opts = getopts::Options::new();
+    opts.optflag(
+        "",
+        "validate",
+        "parse and render all books without writing output, then print a summary",
+    );
+    opts.optflag(
+        "",
+        "review-blacklist",
+        "try only the unsupported books and write their current status to blacklist_review.json",
+    );
+    opts.optflag(
+        "",
+        "list-unsupported",
+        "print the unsupported books with their titles and reasons, then exit",
+    );
+    opts.optflag(
+        "",
+        "streaming",
+        "write books.jsonl (one book per line) while processing instead of books.json",
+    );
+    opts.optflag(
+        "",
+        "pretty",
+        "write indented JSON files instead of compact ones (JSON Lines stay one line per item)",
+    );
+    opts.optflag(
+        "",
+        "furigana",
+        "collect (base, reading) pairs of ruby into furigana.jsonl instead of writing each book",
+    );
+    opts.optflag(
+        "",
+        "metadata-diff",
+        "compare the CSV with the footer of each .txt and write mismatches to metadata_diff.json",
+    );
+
+    opts.optopt(
+        "",
+        "layout",
+        "directory layout of the output (default: by-book-id)",
+        "by-book-id|by-author|by-date",
+    );
+    opts.optopt(
+        "",
+        "sqlite",
+        "write everything into a SQLite database instead of an output directory (updated on re-runs)",
+        "PATH",
+    );
+    opts.optmulti(
+        "",
+        "author",
+        "process only the books of the author (repeatable)",
+        "ID",
+    );
+    opts.optopt(
+        "",
+        "max-books",
+        "stop after N books have reached the parse stage (skipped books are not counted)",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "format",
+        "file format of ruby-txt_parsed / ruby-txt_rendered (default: json)",
+        "json|msgpack",
+    );
 
     let matches = match opts.parse(&args) {
         Ok(m) => m,
@@ -40,78 +130,646 @@ fn get_args() -> Result<Args> {
         .context("path to aozorabunko repository is required")?
         .clone();
     let output_path = matches.free.get(1).map(|s| s.clone());
+    let sqlite_path = matches.opt_str("sqlite");
+    let validate = matches.opt_present("validate");
+    let review_blacklist = matches.opt_present("review-blacklist");
+    let list_unsupported = matches.opt_present("list-unsupported");
+    let pretty = matches.opt_present("pretty");
+    let streaming = matches.opt_present("streaming");
+    let furigana = matches.opt_present("furigana");
+    let metadata_diff = matches.opt_present("metadata-diff");
+    let layout = match matches.opt_str("layout") {
+        Some(layout) => OutputLayout::parse(&layout)?,
+        None => OutputLayout::ByBookId,
+    };
+    let author_ids = matches
+        .opt_strs("author")
+        .iter()
+        .map(|id| {
+            id.parse()
+                .with_context(|| format!("Invalid author ID: {:?}", id))
+        })
+        .collect::<Result<Vec<usize>>>()?;
+    let max_books = matches
+        .opt_str("max-books")
+        .map(|n| {
+            n.parse()
+                .with_context(|| format!("Invalid number of books: {:?}", n))
+        })
+        .transpose()?;
+    let format = match matches.opt_str("format") {
+        Some(format) => OutputFormat::parse(&format)?,
+        None => OutputFormat::Json,
+    };
+
+    ensure!(
+        !(validate && output_path.is_some()),
+        "output path cannot be specified with --validate"
+    );
+    ensure!(
+        !(sqlite_path.is_some() && (validate || output_path.is_some() || furigana)),
+        "--sqlite cannot be specified with --validate, --furigana or output path"
+    );
+    // ディレクトリに書き出すときの設定なので、SQLite には効かない
+    ensure!(
+        !(sqlite_path.is_some()
+            && (streaming || matches.opt_present("layout") || matches.opt_present("format"))),
+        "--sqlite cannot be specified with --streaming, --layout or --format"
+    );
+    ensure!(
+        !(furigana && output_path.is_none()),
+        "output path is required with --furigana"
+    );
 
     Ok(Args {
         aozorabunko_path,
         output_path,
+        sqlite_path,
+        validate,
+        review_blacklist,
+        list_unsupported,
+        layout,
+        format,
+        pretty,
+        streaming,
+        furigana,
+        author_ids,
+        max_books,
+        metadata_diff,
     })
 }
 
 // bad practice?
 enum BuildOut {
     Null,
-    File { root: PathBuf },
+    File {
+        root: PathBuf,
+        layout: OutputLayout,
+        format: OutputFormat,
+        pretty: bool,
+        books_jsonl: Option<File>,    // --streaming のとき
+        furigana_jsonl: Option<File>, // --furigana のとき
+    },
+    // 全体を 1 つのトランザクションで書き、finish で確定する
+    #[cfg(feature = "sqlite")]
+    Sqlite {
+        connection: rusqlite::Connection,
+    },
+}
+
+// 各作品の出力先のディレクトリ構成
+#[derive(Debug, Clone, Copy)]
+#[allow(clippy::enum_variant_names)]
+enum OutputLayout {
+    ByBookId, // {root}/book/{book_id}/
+    ByAuthor, // {root}/author/{author_id}/book/{book_id}/
+    ByDate,   // {root}/{year}/{month}/{book_id}/ (公開日、月が分からなければ month は unknown)
+}
+
+impl OutputLayout {
+    fn parse(layout: &str) -> Result<Self> {
+        match layout {
+            "by-book-id" => Ok(Self::ByBookId),
+            "by-author" => Ok(Self::ByAuthor),
+            "by-date" => Ok(Self::ByDate),
+            _ => bail!("Unknown layout: {}", layout),
+        }
+    }
+
+    fn book_directory(
+        &self,
+        root: &Path,
+        book: &Book,
+        author_id: Option<usize>,
+    ) -> Result<PathBuf> {
+        Ok(match self {
+            Self::ByBookId => root.join(format!("book/{}", book.id)),
+            Self::ByAuthor => {
+                let author_id = author_id
+                    .with_context(|| format!("Author of book {} is not found", book.id))?;
+                root.join(format!("author/{}/book/{}", author_id, book.id))
+            }
+            Self::ByDate => {
+                // 月が分からないものは unknown に置く
+                let (year, month) = match book.published_at {
+                    Date::YM { year, month } | Date::YMD { year, month, .. } => {
+                        (year, month.to_string())
+                    }
+                    Date::Y { year } => (year, "unknown".to_owned()),
+                };
+                root.join(format!("{}/{}/{}", year, month, book.id))
+            }
+        })
+    }
+}
+
+// 各作品の解析結果のファイル形式
+#[derive(Debug, Clone, Copy)]
+enum OutputFormat {
+    Json,
+    Msgpack,
+}
+
+impl OutputFormat {
+    fn parse(format: &str) -> Result<Self> {
+        match format {
+            "json" => Ok(Self::Json),
+            "msgpack" => Ok(Self::Msgpack),
+            _ => bail!("Unknown format: {}", format),
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Msgpack => "msgpack",
+        }
+    }
+
+    fn serialize<T: Serialize>(&self, value: &T, pretty: bool) -> Result<Vec<u8>> {
+        Ok(match self {
+            Self::Json => to_json(value, pretty)?.into_bytes(),
+            // JSON と同じく、フィールド名を含めた map として書く
+            Self::Msgpack => rmp_serde::to_vec_named(value)?,
+        })
+    }
+}
+
+// --pretty なら整形する
+fn to_json<T: Serialize>(value: &T, pretty: bool) -> Result<String> {
+    Ok(if pretty {
+        serde_json::to_string_pretty(value)?
+    } else {
+        serde_json::to_string(value)?
+    })
 }
 
 impl BuildOut {
-    fn init_file(root: &str) -> Result<Self> {
+    fn init_file(
+        root: &str,
+        layout: OutputLayout,
+        format: OutputFormat,
+        pretty: bool,
+        streaming: bool,
+        furigana: bool,
+    ) -> Result<Self> {
         let root = PathBuf::from(&root);
         fs::create_dir(&root).context("Failed to create output directory")?;
 
-        Ok(Self::File { root })
+        let books_jsonl = if streaming {
+            Some(File::create(root.join("books.jsonl")).context("Failed to create books.jsonl")?)
+        } else {
+            None
+        };
+
+        let furigana_jsonl = if furigana {
+            Some(
+                File::create(root.join("furigana.jsonl"))
+                    .context("Failed to create furigana.jsonl")?,
+            )
+        } else {
+            None
+        };
+
+        Ok(Self::File {
+            root,
+            layout,
+            format,
+            pretty,
+            books_jsonl,
+            furigana_jsonl,
+        })
+    }
+
+    // 既にあれば追記・更新する
+    #[cfg(feature = "sqlite")]
+    fn init_sqlite(path: &str) -> Result<Self> {
+        let connection = rusqlite::Connection::open(path).context("Failed to open database")?;
+        connection
+            .execute_batch(
+                "
+                CREATE TABLE IF NOT EXISTS books (id INTEGER PRIMARY KEY, json TEXT NOT NULL);
+                CREATE TABLE IF NOT EXISTS authors (id INTEGER PRIMARY KEY, json TEXT NOT NULL);
+                CREATE TABLE IF NOT EXISTS book_authors (
+                    book_id INTEGER NOT NULL,
+                    author_id INTEGER NOT NULL,
+                    author_role TEXT NOT NULL,
+                    PRIMARY KEY (book_id, author_id)
+                );
+                CREATE INDEX IF NOT EXISTS book_authors_author_id ON book_authors (author_id);
+                CREATE TABLE IF NOT EXISTS ruby_txt (
+                    book_id INTEGER PRIMARY KEY,
+                    parsed TEXT NOT NULL,
+                    rendered TEXT NOT NULL
+                );
+                ",
+            )
+            .context("Failed to create tables")?;
+
+        // 途中で失敗したときは何も書かれないようにする
+        connection
+            .execute_batch("BEGIN")
+            .context("Failed to begin transaction")?;
+
+        Ok(Self::Sqlite { connection })
+    }
+
+    #[cfg(not(feature = "sqlite"))]
+    fn init_sqlite(_path: &str) -> Result<Self> {
+        bail!("--sqlite requires building with the sqlite feature")
+    }
+
+    // 書き出しを確定する
+    fn finish(self) -> Result<()> {
+        #[cfg(feature = "sqlite")]
+        if let BuildOut::Sqlite { connection } = &self {
+            connection
+                .execute_batch("COMMIT")
+                .context("Failed to commit database")?;
+        }
+
+        Ok(())
     }
 
     fn save_aozorabunko_index_list(
         &self,
         aozorabunko_index_list: &AozorabunkoIndexList,
     ) -> Result<()> {
-        if let BuildOut::File { root } = &self {
-            fs::write(
-                &root.join("books.json"),
-                serde_json::to_string(&aozorabunko_index_list.books)?,
-            )?;
+        #[cfg(feature = "sqlite")]
+        if let BuildOut::Sqlite { connection } = &self {
+            for book in &aozorabunko_index_list.books {
+                connection.execute(
+                    "INSERT INTO books (id, json) VALUES (?1, ?2)
+                    ON CONFLICT (id) DO UPDATE SET json = excluded.json",
+                    (book.id, serde_json::to_string(book)?),
+                )?;
+            }
+
+            for author in &aozorabunko_index_list.authors {
+                connection.execute(
+                    "INSERT INTO authors (id, json) VALUES (?1, ?2)
+                    ON CONFLICT (id) DO UPDATE SET json = excluded.json",
+                    (author.id, serde_json::to_string(author)?),
+                )?;
+            }
+
+            for ba in &aozorabunko_index_list.book_authors {
+                connection.execute(
+                    "INSERT INTO book_authors (book_id, author_id, author_role) VALUES (?1, ?2, ?3)
+                    ON CONFLICT (book_id, author_id) DO UPDATE SET author_role = excluded.author_role",
+                    (ba.book_id, ba.author_id, &ba.author_role),
+                )?;
+            }
+        }
 
+        // books.json は処理した作品のみを save_books で書く
+        if let BuildOut::File { root, pretty, .. } = &self {
             fs::write(
                 &root.join("authors.json"),
-                serde_json::to_string(&aozorabunko_index_list.authors)?,
+                to_json(&aozorabunko_index_list.authors, *pretty)?,
             )?;
 
             fs::write(
                 &root.join("book_authors.json"),
-                serde_json::to_string(&aozorabunko_index_list.book_authors)?,
+                to_json(&aozorabunko_index_list.book_authors, *pretty)?,
             )?;
         }
 
         Ok(())
     }
 
+    // --author・--max-books で絞ったときも books.jsonl と同じ作品を書く
+    fn save_books(&self, books: &[&Book]) -> Result<()> {
+        if let BuildOut::File {
+            root,
+            pretty,
+            books_jsonl: None,
+            ..
+        } = &self
+        {
+            fs::write(&root.join("books.json"), to_json(&books, *pretty)?)
+                .context("Failed to write books.json")?;
+        }
+
+        Ok(())
+    }
+
+    fn append_book(&self, book: &Book) -> Result<()> {
+        if let BuildOut::File {
+            books_jsonl: Some(file),
+            ..
+        } = &self
+        {
+            let mut file = file;
+            writeln!(file, "{}", serde_json::to_string(book)?)
+                .context("Failed to write books.jsonl")?;
+        }
+
+        Ok(())
+    }
+
+    // 同じ作品の中で重複するものはまとめて数える
+    fn append_furigana(&self, book: &Book, rendered: &RenderedRubyTxt) -> Result<()> {
+        if let BuildOut::File {
+            furigana_jsonl: Some(file),
+            ..
+        } = &self
+        {
+            let mut counts = BTreeMap::<(String, String), usize>::new();
+            for pair in ruby_pairs(rendered) {
+                *counts.entry(pair).or_default() += 1;
+            }
+
+            let mut file = file;
+            for ((base, reading), count) in counts {
+                let item = FuriganaItem {
+                    base,
+                    reading,
+                    book_id: book.id,
+                    count,
+                };
+                writeln!(file, "{}", serde_json::to_string(&item)?)
+                    .context("Failed to write furigana.jsonl")?;
+            }
+        }
+
+        Ok(())
+    }
+
     fn save_book_ruby_txt(
         &self,
-        book_id: usize,
+        book: &Book,
+        author_id: Option<usize>,
         parsed: &ParsedRubyTxt,
         rendered: &RenderedRubyTxt,
     ) -> Result<()> {
-        if let BuildOut::File { root } = &self {
-            let book_directory_path = &root.join(format!("book/{}", book_id));
+        if let BuildOut::File {
+            root,
+            layout,
+            format,
+            pretty,
+            ..
+        } = &self
+        {
+            let book_directory_path = &layout.book_directory(root, book, author_id)?;
             fs::create_dir_all(&book_directory_path).unwrap();
 
             fs::write(
-                &book_directory_path.join("ruby-txt_parsed.json"),
-                serde_json::to_string(&parsed).unwrap(),
+                &book_directory_path.join(format!("ruby-txt_parsed.{}", format.extension())),
+                format.serialize(&parsed, *pretty)?,
             )
             .unwrap();
 
             fs::write(
-                &book_directory_path.join("ruby-txt_rendered.json"),
-                serde_json::to_string(&rendered).unwrap(),
+                &book_directory_path.join(format!("ruby-txt_rendered.{}", format.extension())),
+                format.serialize(&rendered, *pretty)?,
             )
             .unwrap();
         }
 
+        #[cfg(feature = "sqlite")]
+        if let BuildOut::Sqlite { connection } = &self {
+            connection
+                .execute(
+                    "INSERT INTO ruby_txt (book_id, parsed, rendered) VALUES (?1, ?2, ?3)
+                    ON CONFLICT (book_id) DO UPDATE
+                    SET parsed = excluded.parsed, rendered = excluded.rendered",
+                    (
+                        book.id,
+                        serde_json::to_string(parsed)?,
+                        serde_json::to_string(rendered)?,
+                    ),
+                )
+                .with_context(|| format!("Failed to write book {} to database", book.id))?;
+        }
+
         Ok(())
     }
 }
 
+// furigana.jsonl の 1 行
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FuriganaItem {
+    base: String,
+    reading: String,
+    book_id: usize,
+    count: usize, // この作品での出現回数
+}
+
+// 1 作品の処理結果
+enum BookStatus {
+    Skipped,
+    Succeeded,
+    ReadFailed(anyhow::Error), // zip を開けない、.txt を選べない・読めない
+    ParseFailed(anyhow::Error),
+    RenderFailed(anyhow::Error),
+}
+
+// 処理結果の集計
+#[derive(Default)]
+struct ValidationSummary {
+    books_seen: usize,
+    skipped_copyright: usize,
+    skipped_external: usize, // 青空文庫の外にあるもの・zip でないもの
+    skipped: usize,          // それ以外 (.txt がない、ルビなし、未対応など)
+    succeeded: usize,
+    read_failures: Vec<(usize, anyhow::Error)>,
+    parse_failures: Vec<(usize, anyhow::Error)>,
+    render_failures: Vec<(usize, anyhow::Error)>,
+    annotations: BTreeMap<String, usize>, // パースできた作品に現れた要素の種類ごとの数
+}
+
+impl ValidationSummary {
+    // 文字列と改行以外を数える
+    fn count_annotations(&mut self, parsed: &ParsedRubyTxt) {
+        for el in parsed
+            .header
+            .iter()
+            .chain(&parsed.body)
+            .chain(&parsed.footer)
+        {
+            if matches!(
+                el,
+                ParsedRubyTxtElement::String { .. } | ParsedRubyTxtElement::NewLine
+            ) {
+                continue;
+            }
+
+            *self.annotations.entry(el.kind().to_owned()).or_default() += 1;
+        }
+    }
+
+    fn stats(&self) -> RunStats {
+        RunStats {
+            books_seen: self.books_seen,
+            succeeded: self.succeeded,
+            skipped_copyright: self.skipped_copyright,
+            skipped_external: self.skipped_external,
+            skipped: self.skipped,
+            read_failed: self.read_failures.len(),
+            parse_failed: self.parse_failures.len(),
+            render_failed: self.render_failures.len(),
+            annotations: self.annotations.clone(),
+        }
+    }
+
+    // --validate のときは失敗した作品も示す
+    fn print_failures(&self) {
+        println!("Read failures: {}", self.read_failures.len());
+        for (book_id, error) in &self.read_failures {
+            println!("  {}: {:#}", book_id, error);
+        }
+
+        println!("Parse failures: {}", self.parse_failures.len());
+        for (book_id, error) in &self.parse_failures {
+            println!("  {}: {:#}", book_id, error);
+        }
+
+        println!("Render failures: {}", self.render_failures.len());
+        for (book_id, error) in &self.render_failures {
+            println!("  {}: {:#}", book_id, error);
+        }
+    }
+}
+
+// 実行の最後に表示し、stats.json に書き出すもの
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RunStats {
+    books_seen: usize,
+    succeeded: usize,
+    skipped_copyright: usize,
+    skipped_external: usize,
+    skipped: usize,
+    read_failed: usize,
+    parse_failed: usize,
+    render_failed: usize,
+    annotations: BTreeMap<String, usize>,
+}
+
+impl RunStats {
+    fn print(&self) {
+        println!("Books: {}", self.books_seen);
+        println!("Succeeded: {}", self.succeeded);
+        println!("Skipped (copyright): {}", self.skipped_copyright);
+        println!("Skipped (external): {}", self.skipped_external);
+        println!("Skipped: {}", self.skipped);
+        println!("Read failed: {}", self.read_failed);
+        println!("Parse failed: {}", self.parse_failed);
+        println!("Render failed: {}", self.render_failed);
+
+        println!("Annotations:");
+        for (name, count) in &self.annotations {
+            println!("  {}: {}", name, count);
+        }
+    }
+}
+
+// --metadata-diff での 1 つの食い違い
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct MetadataDiffItem {
+    book_id: usize,
+    field: &'static str,
+    csv: String,
+    txt: String,
+}
+
+// 片方にしかないものは比べない
+fn metadata_diffs(book: &Book, footer: &FooterMetadata) -> Vec<MetadataDiffItem> {
+    let trim_brackets = |s: &str| {
+        s.trim_matches(&['「', '」', '『', '』', ' ', '　'][..])
+            .to_owned()
+    };
+
+    let fields = [
+        (
+            "originalBookTitle",
+            book.original_book.first().map(|b| b.title.as_str()),
+            footer.original_book_title.as_deref(),
+        ),
+        (
+            "inputterName",
+            Some(book.inputter_name.as_str()),
+            footer.inputter_name.as_deref(),
+        ),
+        (
+            "proofreaderName",
+            Some(book.proofreader_name.as_str()),
+            footer.proofreader_name.as_deref(),
+        ),
+    ];
+
+    let mut diffs = Vec::new();
+    for (field, csv, txt) in fields {
+        let (Some(csv), Some(txt)) = (csv, txt) else {
+            continue;
+        };
+        let (csv, txt) = (trim_brackets(csv), trim_brackets(txt));
+        if csv.is_empty() || txt.is_empty() || csv == txt {
+            continue;
+        }
+        diffs.push(MetadataDiffItem {
+            book_id: book.id,
+            field,
+            csv,
+            txt,
+        });
+    }
+    diffs
+}
+
+// --review-blacklist での 1 作品の結果
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BlacklistReviewItem {
+    book_id: usize,
+    unsupported_to_parse: bool,
+    unsupported_to_render: bool,
+    status: BlacklistReviewStatus,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum BlacklistReviewStatus {
+    Skipped,
+    Succeeded,
+    ReadFailed,
+    ParseFailed,
+    RenderFailed,
+}
+
+impl BlacklistReviewItem {
+    fn new(book_id: usize, status: &BookStatus) -> Self {
+        let (status, error) = match status {
+            BookStatus::Skipped => (BlacklistReviewStatus::Skipped, None),
+            BookStatus::Succeeded => (BlacklistReviewStatus::Succeeded, None),
+            BookStatus::ReadFailed(error) => (
+                BlacklistReviewStatus::ReadFailed,
+                Some(format!("{:#}", error)),
+            ),
+            BookStatus::ParseFailed(error) => (
+                BlacklistReviewStatus::ParseFailed,
+                Some(format!("{:#}", error)),
+            ),
+            BookStatus::RenderFailed(error) => (
+                BlacklistReviewStatus::RenderFailed,
+                Some(format!("{:#}", error)),
+            ),
+        };
+
+        Self {
+            book_id,
+            unsupported_to_parse: !is_supported_to_parse(&book_id),
+            unsupported_to_render: !is_supported_to_render(&book_id),
+            status,
+            error,
+        }
+    }
+}
+
 fn main() -> Result<()> {
     let args = get_args()?;
 
@@ -123,25 +781,51 @@ fn main() -> Result<()> {
     );
 
     let out = if let Some(output_path) = &args.output_path {
-        BuildOut::init_file(&output_path)
-            .with_context(|| format!("Failed to output directory: {}", &output_path))?
+        BuildOut::init_file(
+            &output_path,
+            args.layout,
+            args.format,
+            args.pretty,
+            args.streaming,
+            args.furigana,
+        )
+        .with_context(|| format!("Failed to output directory: {}", &output_path))?
+    } else if let Some(sqlite_path) = &args.sqlite_path {
+        BuildOut::init_sqlite(sqlite_path)
+            .with_context(|| format!("Failed to output database: {}", sqlite_path))?
     } else {
         BuildOut::Null
     };
 
     println!("Processing list_person_all_extended...");
 
+    // UTF-8 版がなければ Shift_JIS 版を読む
     let aozorabunko_index_list = {
         let csv_zip_path = aozorabunko_path.join("index_pages/list_person_all_extended_utf8.zip");
-        let csv_zip_file = File::open(csv_zip_path).unwrap();
-        let mut csv_zip_reader = ZipReader::new(csv_zip_file)?;
+        if csv_zip_path.exists() {
+            let mut csv_zip_reader = ZipReader::open(&csv_zip_path)?;
+            let csv_entry = csv_zip_reader.get_by_path("list_person_all_extended_utf8.csv")?;
 
-        let mut csv_entry = csv_zip_reader.get_by_path("list_person_all_extended_utf8.csv")?;
-        let csv_data = csv_entry.as_string()?;
+            parse_index_list_from_reader(csv_entry)?
+        } else {
+            let csv_zip_path = aozorabunko_path.join("index_pages/list_person_all_extended.zip");
+            let mut csv_zip_reader = ZipReader::open(&csv_zip_path)?;
+            let mut csv_entry = csv_zip_reader.get_by_path("list_person_all_extended.csv")?;
 
-        parse_list_person_all_extended_csv(&csv_data)?
+            parse_index_list_from_shift_jis(&csv_entry.as_bytes()?)
+                .with_context(|| format!("Failed to read {}", csv_zip_path.display()))?
+        }
     };
 
+    if args.list_unsupported {
+        print_unsupported_books(&aozorabunko_index_list);
+        return Ok(());
+    }
+
+    if args.validate {
+        println!("{}", aozorabunko_index_list.statistics());
+    }
+
     out.save_aozorabunko_index_list(&aozorabunko_index_list)?;
 
     println!("Finished.");
@@ -158,72 +842,223 @@ fn main() -> Result<()> {
 
     // 著作権がある本の ID
     let mut book_ids_with_copyright = HashSet::new();
-    for ba in aozorabunko_index_list.book_authors {
+    for ba in &aozorabunko_index_list.book_authors {
         if author_ids_with_copyright.contains(&ba.author_id) {
             book_ids_with_copyright.insert(ba.book_id);
         }
     }
     let book_ids_with_copyright = book_ids_with_copyright;
 
-    let pb = create_progress_bar(aozorabunko_index_list.books.len() as u64);
-    for book in aozorabunko_index_list.books.iter().progress_with(pb) {
+    // --author で指定した人物の作品
+    let target_book_ids: Option<HashSet<_>> = (!args.author_ids.is_empty()).then(|| {
+        aozorabunko_index_list
+            .book_authors
+            .iter()
+            .filter(|ba| args.author_ids.contains(&ba.author_id))
+            .map(|ba| ba.book_id)
+            .collect()
+    });
+    let target_books: Vec<_> = aozorabunko_index_list
+        .books
+        .iter()
+        .filter(|b| {
+            target_book_ids
+                .as_ref()
+                .is_none_or(|ids| ids.contains(&b.id))
+        })
+        .collect();
+
+    // 処理した作品 (books.json)
+    let mut seen_books = Vec::new();
+
+    let mut summary = ValidationSummary::default();
+    let mut blacklist_review = Vec::new();
+    let mut metadata_diff = Vec::new();
+
+    // パースまで進んだ作品の数 (--max-books)
+    let mut parsed_books = 0;
+
+    let pb = create_progress_bar(target_books.len() as u64);
+    for book in target_books.into_iter().progress_with(pb.clone()) {
+        if args.max_books.is_some_and(|n| parsed_books >= n) {
+            break;
+        }
+
+        out.append_book(book)?;
+        seen_books.push(book);
+        summary.books_seen += 1;
+
+        if args.review_blacklist
+            && is_supported_to_parse(&book.id)
+            && is_supported_to_render(&book.id)
+        {
+            continue;
+        }
+
         // 著作権があるものは飛ばす
         if book_ids_with_copyright.contains(&book.id) {
+            summary.skipped_copyright += 1;
             continue;
         }
 
         // .txt
         if let Some(txt_url) = &book.txt_url {
-            if !txt_url.starts_with("https://www.aozora.gr.jp/") {
+            // 青空文庫の外にあるものや zip でないものは飛ばす
+            let Some(txt_zip_path) = book.txt_zip_local_path(&aozorabunko_path) else {
+                summary.skipped_external += 1;
                 continue;
-            }
-
-            (|| {
-                ensure!(&txt_url.ends_with("zip"), "Not zip file");
+            };
 
-                let txt_zip_path =
-                    aozorabunko_path.join(&txt_url["https://www.aozora.gr.jp/".len()..]);
-                let txt_zip_file = File::open(&txt_zip_path).unwrap();
-                let mut txt_zip_reader = ZipReader::new(txt_zip_file)?;
+            let status = (|| -> Result<BookStatus> {
+                let txt = match (|| -> Result<String> {
+                    let mut txt_zip_reader = ZipReader::open(&txt_zip_path)?;
 
-                let mut txt_bytes = None;
-                for i in 0..txt_zip_reader.len() {
-                    let mut entry = txt_zip_reader.get_by_index(i).unwrap();
-                    if !entry.name().to_lowercase().ends_with(".txt") {
-                        continue;
+                    // .txt が複数あって ruby を含むものを選んだときは記録する
+                    let (txt_index, selected) = find_txt_entry(&mut txt_zip_reader, true)?;
+                    let mut txt_entry = txt_zip_reader.get_by_index(txt_index)?;
+                    if selected {
+                        pb.println(format!("{}: selected {}", book.id, txt_entry.name()));
                     }
+                    txt_entry.as_shift_jis_string()
+                })() {
+                    Ok(txt) => txt,
+                    Err(error) => return Ok(BookStatus::ReadFailed(error)),
+                };
+
+                if !txt_url.contains("ruby") {
+                    return Ok(BookStatus::Skipped);
+                }
 
-                    ensure!(txt_bytes.is_none(), ".txt file exists more than 1");
+                let tokens = match tokenize_ruby_txt(&txt).context("Failed to tokenize") {
+                    Ok(tokens) => tokens,
+                    Err(error) => return Ok(BookStatus::ParseFailed(error)),
+                };
 
-                    txt_bytes = Some(entry.as_bytes()?);
+                if !args.review_blacklist && !is_supported_to_parse(&book.id) {
+                    return Ok(BookStatus::Skipped);
                 }
 
-                let txt_bytes = txt_bytes.context(".txt file is not found")?;
-                let txt = encoding_rs::SHIFT_JIS.decode(&txt_bytes).0.into_owned();
+                parsed_books += 1;
+                let parse_options = ParseOptions {
+                    on_warning: Some(Arc::new({
+                        let pb = pb.clone();
+                        let book_id = book.id;
+                        move |warning: &ParseWarning| {
+                            pb.println(format!("{}: {}", book_id, warning))
+                        }
+                    })),
+                    ..Default::default()
+                };
+                let parsed = match parse_ruby_txt_with_options(&tokens, &parse_options)
+                    .context("Failed to parse")
+                {
+                    Ok(parsed) => parsed,
+                    Err(error) => return Ok(BookStatus::ParseFailed(error)),
+                };
+                summary.count_annotations(&parsed);
 
-                if txt_url.contains("ruby") {
-                    let tokens = tokenize_ruby_txt(&txt).context("Failed to tokenize")?;
+                if args.metadata_diff {
+                    metadata_diff.extend(metadata_diffs(book, &parse_footer_metadata(&parsed)));
+                }
 
-                    if is_supported_to_parse(&book.id) {
-                        let parsed = parse_ruby_txt(&tokens).context("Failed to parse")?;
+                if !args.review_blacklist && !is_supported_to_render(&book.id) {
+                    return Ok(BookStatus::Skipped);
+                }
 
-                        if is_supported_to_render(&book.id) {
-                            let rendered = render_ruby_txt(&parsed).context("Failed to render")?;
+                let render_options = RenderOptions {
+                    old_kana: book.uses_old_kana(),
+                    ..Default::default()
+                };
+                let rendered = match render_ruby_txt_with_options(&parsed, &render_options)
+                    .context("Failed to render")
+                {
+                    Ok(rendered) => rendered,
+                    Err(error) => return Ok(BookStatus::RenderFailed(error)),
+                };
 
-                            out.save_book_ruby_txt(book.id, &parsed, &rendered)?;
-                        }
+                if !args.review_blacklist {
+                    let author_id = aozorabunko_index_list
+                        .authors_of(book.id)
+                        .first()
+                        .map(|a| a.id);
+                    if args.furigana {
+                        out.append_furigana(book, &rendered)?;
+                    } else {
+                        out.save_book_ruby_txt(book, author_id, &parsed, &rendered)?;
                     }
                 }
 
-                Ok(())
+                Ok(BookStatus::Succeeded)
             })()
             .with_context(|| format!("Failed to process book zip: {:?}", &book))?;
+
+            if args.review_blacklist {
+                blacklist_review.push(BlacklistReviewItem::new(book.id, &status));
+                continue;
+            }
+
+            match status {
+                BookStatus::ReadFailed(error)
+                | BookStatus::ParseFailed(error)
+                | BookStatus::RenderFailed(error)
+                    if !args.validate =>
+                {
+                    return Err(error.context(format!("Failed to process book zip: {:?}", &book)));
+                }
+                BookStatus::Skipped => summary.skipped += 1,
+                BookStatus::Succeeded => summary.succeeded += 1,
+                BookStatus::ReadFailed(error) => summary.read_failures.push((book.id, error)),
+                BookStatus::ParseFailed(error) => summary.parse_failures.push((book.id, error)),
+                BookStatus::RenderFailed(error) => summary.render_failures.push((book.id, error)),
+            }
+        } else {
+            summary.skipped += 1;
         }
     }
 
+    out.save_books(&seen_books)?;
+
     println!("Finished.");
 
-    Ok(())
+    let stats = summary.stats();
+    stats.print();
+    if args.validate {
+        summary.print_failures();
+    }
+    if let BuildOut::File { root, .. } = &out {
+        let stats_path = root.join("stats.json");
+        fs::write(&stats_path, to_json(&stats, args.pretty)?)
+            .with_context(|| format!("Failed to write {}", stats_path.display()))?;
+    }
+
+    if args.review_blacklist {
+        println!("Unsupported books which now succeed:");
+        for item in &blacklist_review {
+            if matches!(item.status, BlacklistReviewStatus::Succeeded) {
+                println!("  {}", item.book_id);
+            }
+        }
+
+        let review_path = match &out {
+            BuildOut::File { root, .. } => root.join("blacklist_review.json"),
+            _ => PathBuf::from("blacklist_review.json"),
+        };
+        fs::write(&review_path, to_json(&blacklist_review, args.pretty)?)
+            .with_context(|| format!("Failed to write {}", review_path.display()))?;
+    }
+
+    if args.metadata_diff {
+        println!("Metadata mismatches: {}", metadata_diff.len());
+
+        let diff_path = match &out {
+            BuildOut::File { root, .. } => root.join("metadata_diff.json"),
+            _ => PathBuf::from("metadata_diff.json"),
+        };
+        fs::write(&diff_path, to_json(&metadata_diff, args.pretty)?)
+            .with_context(|| format!("Failed to write {}", diff_path.display()))?;
+    }
+
+    out.finish()
 }
 
 fn create_progress_bar(len: u64) -> ProgressBar {
@@ -238,68 +1073,119 @@ fn create_progress_bar(len: u64) -> ProgressBar {
     pb
 }
 
-fn is_supported_to_parse(book_id: &usize) -> bool {
-    ![
-        // "【テキスト中に現れる記号について】" が "《テキスト中に現れる記号について》" になっている
-        18379, // 楠山正雄「くらげのお使い」
-        45670, // 林不忘「魔像」
-        45664, // 福沢諭吉「旧藩情」
-        46228, // 林不忘「巷説享保図絵」
-        46229, // 林不忘「つづれ烏羽玉」
+// パースに対応していない作品 (作品 ID => 理由)
+static UNSUPPORTED_TO_PARSE: Lazy<HashMap<usize, &'static str>> = Lazy::new(|| {
+    const KIGOU: &str =
+        "\"【テキスト中に現れる記号について】\" が \"《テキスト中に現れる記号について》\" になっている";
+    const NAZO: &str = "不明な書式: 謎 annotation";
+
+    HashMap::from([
+        (18379, KIGOU), // 楠山正雄「くらげのお使い」
+        (45670, KIGOU), // 林不忘「魔像」
+        (45664, KIGOU), // 福沢諭吉「旧藩情」
+        (46228, KIGOU), // 林不忘「巷説享保図絵」
+        (46229, KIGOU), // 林不忘「つづれ烏羽玉」
         //
-        // "底本：" のミス
-        1871, // エドガー・アラン・ポー「落穴と振子」　"底本「"
-        2526, // エドガー・アラン・ポー「早すぎる埋葬」　"底本「"
+        (1871, "\"底本：\" のミス: \"底本「\""), // エドガー・アラン・ポー「落穴と振子」
+        (2526, "\"底本：\" のミス: \"底本「\""), // エドガー・アラン・ポー「早すぎる埋葬」
         //
-        // 不明な書式
-        395,   // 萩原朔太郎「散文詩集『田舎の時計　他十二篇』」
-        455,   // 宮沢賢治「ガドルフの百合」
-        906,   // 横光利一「時間」
-        909,   // 横光利一「鳥」
-        1255,  // 海野十三「海野十三敗戦日記」　謎 annotation
-        4832,  // 宮本百合子「日記」『一九一三年（大正二年）』　謎 annotation
-        46237, // 宮本百合子「日記」『一九一七年（大正六年）』　謎 annotation
-        46241, // 宮本百合子「日記」『一九二二年（大正十一年）』　謎 annotation
-        46244, // 宮本百合子「日記」『一九二六年（大正十五年・昭和元年）』　謎 annotation
-        46247, // 宮本百合子「日記」『一九二九年（昭和四年）』　謎 annotation
+        (395, "不明な書式"), // 萩原朔太郎「散文詩集『田舎の時計　他十二篇』」
+        (455, "不明な書式"), // 宮沢賢治「ガドルフの百合」
+        (906, "不明な書式"), // 横光利一「時間」
+        (909, "不明な書式"), // 横光利一「鳥」
+        (1255, NAZO),        // 海野十三「海野十三敗戦日記」
+        (4832, NAZO),        // 宮本百合子「日記」『一九一三年（大正二年）』
+        (46237, NAZO),       // 宮本百合子「日記」『一九一七年（大正六年）』
+        (46241, NAZO),       // 宮本百合子「日記」『一九二二年（大正十一年）』
+        (46244, NAZO),       // 宮本百合子「日記」『一九二六年（大正十五年・昭和元年）』
+        (46247, NAZO),       // 宮本百合子「日記」『一九二九年（昭和四年）』
         //
-        // 細かいミス
-        2168,  // 與謝野寛、與謝野晶子「巴里より」　"一番向｜《むか》うにある"
-        2218,  // 若山牧水「樹木とその葉」　"しん［＃「しん」傍点］"
-        24456, // 南方熊楠「棄老傳説に就て」　"底本・" が "底本・初出："
-        43035, // 岡本かの子「花は勁し」　"底本" が "定本" になっている
-        56634, // 梅崎春生「幻化」　"「もう一杯｜《く》呉れ」"
+        (2218, "細かいミス: \"しん［＃「しん」傍点］\""), // 若山牧水「樹木とその葉」
+        (43035, "細かいミス: \"底本\" が \"定本\" になっている"), // 岡本かの子「花は勁し」
         //
-        // aozorabunko-json が未対応
-        1317,  // 小栗虫太郎「黒死館殺人事件」　画像にルビ
-        1897,  // 正岡子規「墨汁一滴」　不明な外字 "※［＃「麾−毛」、42-8］"
-        2032, // 宮本百合子「風に乗って来るコロポックル」　"《シサム》［＃「ム」は小書き片仮名ム、1-6-89］"
-        47202, // 折口信夫「用言の発展」　"※［＃ハングル文字、「ロ／亅／一」、439-17］"
-        51729, // 「古事記」　不明な外字 "※［＃「討／貝」、406-2-9］"
-        60608, // 上田秋成「雨月物語」　画像を字寄せ
-    ]
-    .contains(book_id)
-}
+        (1317, "aozorabunko-json が未対応: 画像にルビ"), // 小栗虫太郎「黒死館殺人事件」
+        (
+            1897,
+            "aozorabunko-json が未対応: 不明な外字 \"※［＃「麾−毛」、42-8］\"",
+        ), // 正岡子規「墨汁一滴」
+        (
+            2032,
+            "aozorabunko-json が未対応: \"《シサム》［＃「ム」は小書き片仮名ム、1-6-89］\"",
+        ), // 宮本百合子「風に乗って来るコロポックル」
+        (
+            47202,
+            "aozorabunko-json が未対応: \"※［＃ハングル文字、「ロ／亅／一」、439-17］\"",
+        ), // 折口信夫「用言の発展」
+        (
+            51729,
+            "aozorabunko-json が未対応: 不明な外字 \"※［＃「討／貝」、406-2-9］\"",
+        ), // 「古事記」
+        (60608, "aozorabunko-json が未対応: 画像を字寄せ"), // 上田秋成「雨月物語」
+    ])
+});
 
-fn is_supported_to_render(book_id: &usize) -> bool {
-    ![
-        // 細かいミス
-        2590,  // 倉田百三「愛と認識との出発」　地寄せの記述ミス
-        2733,  // 宮本百合子「ソヴェトの芝居」　地付きの記述ミス
-        44907, // 桑原隲藏「支那の孝道殊に法律上より觀たる支那の孝道」　"［＃ここで字下げ終わり］" の前に謎の空白
-        53104, // 柳田国男「木綿以前の事」　"［＃５字下げ］" の前に謎の空白
-        57532, // 江戸川乱歩「新宝島」　"［＃３字下げ］" の前に謎の空白
-        58209, // 野村胡堂「銭形平次捕物控」　"［＃７字下げ］" の前に謎の空白
+// レンダリングに対応していない作品 (作品 ID => 理由)
+static UNSUPPORTED_TO_RENDER: Lazy<HashMap<usize, &'static str>> = Lazy::new(|| {
+    HashMap::from([
+        (2590, "細かいミス: 地寄せの記述ミス"), // 倉田百三「愛と認識との出発」
+        (2733, "細かいミス: 地付きの記述ミス"), // 宮本百合子「ソヴェトの芝居」
+        (
+            44907,
+            "細かいミス: \"［＃ここで字下げ終わり］\" の前に謎の空白",
+        ), // 桑原隲藏「支那の孝道殊に法律上より觀たる支那の孝道」
+        (53104, "細かいミス: \"［＃５字下げ］\" の前に謎の空白"), // 柳田国男「木綿以前の事」
+        (57532, "細かいミス: \"［＃３字下げ］\" の前に謎の空白"), // 江戸川乱歩「新宝島」
+        (58209, "細かいミス: \"［＃７字下げ］\" の前に謎の空白"), // 野村胡堂「銭形平次捕物控」
         //
-        // 不明な書式
-        56258, // 山崎富栄「雨の玉川心中」　"　　十一月三十日［＃１１字下げ］富栄"
-        57464, // 中谷宇吉郎「冬彦夜話」　"［＃ここで字下げ終わり］" が独立した行でない
-        60609, // 上田秋成（鵜月洋訳）「雨月物語」『現代語訳　雨月物語』　"［＃１字下げ］書肆［＃地から３字上げ］"
+        (
+            56258,
+            "不明な書式: \"　　十一月三十日［＃１１字下げ］富栄\"",
+        ), // 山崎富栄「雨の玉川心中」
+        (
+            57464,
+            "不明な書式: \"［＃ここで字下げ終わり］\" が独立した行でない",
+        ), // 中谷宇吉郎「冬彦夜話」
         //
-        // aozorabunko-json が未対応
-        4462,  // 宮沢賢治「文語詩稿　一百篇」　"［＃改ページ］" についての説明が入っている
-        49825, // 下村湖人「青年の思索のために」　1 行に 2 つのブロック終わり注記 "［＃ここで小さな文字終わり］［＃ここで字下げ終わり］"
-        55342, // 野村長一「名曲決定盤」　"［＃改ページ］" についての説明が入っている
-    ]
-    .contains(book_id)
+        (
+            4462,
+            "aozorabunko-json が未対応: \"［＃改ページ］\" についての説明が入っている",
+        ), // 宮沢賢治「文語詩稿　一百篇」
+        (
+            49825,
+            "aozorabunko-json が未対応: 1 行に 2 つのブロック終わり注記 \"［＃ここで小さな文字終わり］［＃ここで字下げ終わり］\"",
+        ), // 下村湖人「青年の思索のために」
+        (
+            55342,
+            "aozorabunko-json が未対応: \"［＃改ページ］\" についての説明が入っている",
+        ), // 野村長一「名曲決定盤」
+    ])
+});
+
+fn is_supported_to_parse(book_id: &usize) -> bool {
+    !UNSUPPORTED_TO_PARSE.contains_key(book_id)
+}
+
+fn is_supported_to_render(book_id: &usize) -> bool {
+    !UNSUPPORTED_TO_RENDER.contains_key(book_id)
+}
+
+// --list-unsupported: 未対応の作品を一覧表示する
+fn print_unsupported_books(aozorabunko_index_list: &AozorabunkoIndexList) {
+    let print_list = |name: &str, list: &HashMap<usize, &'static str>| {
+        let mut ids: Vec<_> = list.keys().copied().collect();
+        ids.sort();
+
+        println!("{} ({} books):", name, ids.len());
+        for id in ids {
+            let title = aozorabunko_index_list
+                .book(id)
+                .map_or("(not found in index)", |b| b.title.as_str());
+            println!("  - book {}: {}", id, title);
+            println!("    reason: {}", list[&id]);
+        }
+    };
+
+    print_list("Unsupported to parse", &UNSUPPORTED_TO_PARSE);
+    println!();
+    print_list("Unsupported to render", &UNSUPPORTED_TO_RENDER);
 }
@@ -0,0 +1,12 @@
+// よく使う型と関数
+// use aozorabunko_json::prelude::*;
+
+#[cfg(feature = "index")]
+pub use crate::list_person_all_extended_csv::parser::{
+    AozorabunkoIndexList, Author, Book, BookWithAuthors,
+};
+pub use crate::ruby_txt::{
+    parser::{parse_ruby_txt, ParsedRubyTxt},
+    renderer::{render_ruby_txt, RenderedRubyTxt},
+    tokenizer::tokenize_ruby_txt,
+};
@@ -3,17 +3,26 @@
 // フォーマットから外れたものは基本的にエラーとするが，一部フールプルーフする：
 // - 改行は公式に CR+LF とされているが完全には統一されていない
 // - "底本：" は "底本:" でもよい
+// - "底本：" は "底本・初出：" でもよい
 // - 長いハイフンは "テキスト中に現れる記号について" を示すためとされているが
 //   単なる区切り？としての利用もある
 //   - (例) https://www.aozora.gr.jp/cards/000124/card652.html
 
 mod annotation_parser;
 mod block_parser;
+pub mod decoration_span;
+pub mod diff;
+pub mod footer_metadata;
+pub mod footnote;
 mod gaiji_accent_decomposition_parser;
 mod gaiji_annotation_parser;
+pub mod header_metadata;
+pub mod image;
 pub mod parser;
 mod parser_helper;
+pub mod plain_text;
 pub mod renderer;
+pub mod ruby_pair;
 mod ruby_parser;
 pub mod tokenizer;
-mod utility;
+pub mod utility;
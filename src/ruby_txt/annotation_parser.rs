@@ -4,21 +4,22 @@ use regex::Regex;
 
 use crate::{
     ruby_txt::{
-        block_parser::parse_block,
-        parser::ParsedRubyTxtElement,
+        block_parser::{parse_block, BlockContext},
+        parser::{ParseWarning, ParsedRubyTxtElement},
         tokenizer::RubyTxtToken,
         utility::{
-            BouDecorationSide, BouDecorationStyle, MidashiLevel, MidashiStyle,
+            BouDecorationSide, BouDecorationStyle, KogakiPosition, MidashiLevel, MidashiStyle,
             StringDecorationStyle,
         },
     },
-    utility::str::parse_number,
+    utility::str::parse_number_kanji,
 };
 
 // AnnotationStart ... AnnotationEnd
 pub(super) fn parse_annotation<'a>(
     tokens: &'a [&'a RubyTxtToken],
-) -> Result<(&'a [&'a RubyTxtToken], Option<ParsedRubyTxtElement>)> {
+    ctx: &BlockContext,
+) -> Result<(&'a [&'a RubyTxtToken], Vec<ParsedRubyTxtElement>)> {
     ensure!(matches!(tokens.get(0), Some(RubyTxtToken::AnnotationStart)));
     let tokens = &tokens[1..];
 
@@ -48,15 +49,24 @@ pub(super) fn parse_annotation<'a>(
     let args = &tokens[..end_index];
     let tokens = &tokens[(end_index + 1)..];
 
-    let args = parse_block(args)?;
+    // 注記の中身そのまま
+    let raw: String = args.iter().map(|t| t.to_str()).collect();
+    let source = ctx.options.keep_source.then(|| format!("［＃{}］", raw));
+
+    let args = parse_block(args, &ctx.nested()?)?;
+
+    // ［＃改丁、ページの左右中央］ のように 1 つの注記に指示をまとめたもの
+    if let [ParsedRubyTxtElement::String { value }] = args.as_slice() {
+        if let Some(directives) = split_directives(value)? {
+            return Ok((tokens, with_source(directives, source)));
+        }
+    }
 
     // もっとうまい分岐の仕方がある？
     let annotation = (|| {
         // 空の annotation は "［＃］：入力者注　主に外字の説明や、傍点の位置の指定" のように使われることがある
         if args.len() == 0 {
-            return Ok(Some(ParsedRubyTxtElement::String {
-                value: "［＃］".to_owned(),
-            }));
+            return Ok(Some(ParsedRubyTxtElement::EmptyAnnotation));
         }
 
         let first_arg = match args.first().unwrap() {
@@ -103,10 +113,19 @@ pub(super) fn parse_annotation<'a>(
 
         // "「Vec<BookContentElement>」String" 型
         if first_arg.starts_with('「') && last_arg.contains('」') {
+            // ［＃「○○」に「●●」の注記］ は「●●」の後ろではなく「○○」の後ろまでを対象とする
+            static REGEX_EDITORIAL_NOTE: Lazy<Regex> = Lazy::new(|| {
+                Regex::new(r"」(に「(?P<note>[^「」]*)」)?の注記(?P<rest>.*)$").unwrap()
+            });
+            let target_end = match REGEX_EDITORIAL_NOTE.find(last_arg) {
+                Some(m) => m.start(),
+                None => last_arg.rfind('」').unwrap(),
+            };
+
             let target = match args.len() {
                 1 => {
                     let l = "「".len();
-                    let r = first_arg.rfind('」').unwrap();
+                    let r = target_end;
                     vec![ParsedRubyTxtElement::String {
                         value: first_arg[l..r].to_string(),
                     }]
@@ -124,7 +143,7 @@ pub(super) fn parse_annotation<'a>(
                     };
 
                     let last = {
-                        let r = last_arg.rfind('」').unwrap();
+                        let r = target_end;
                         if 0 < r {
                             Some(ParsedRubyTxtElement::String {
                                 value: last_arg[..r].to_string(),
@@ -152,7 +171,24 @@ pub(super) fn parse_annotation<'a>(
                 }
             };
 
-            let annotation_name = last_arg[last_arg.rfind('」').unwrap()..].to_string();
+            let annotation_name = last_arg[target_end..].to_string();
+
+            // ［＃「○○」に「●●」の注記］
+            // ［＃「○○」の注記…］
+            if let Some(caps) = REGEX_EDITORIAL_NOTE.captures(&annotation_name) {
+                let note = match caps.name("note") {
+                    Some(note) => note.as_str(),
+                    None => caps
+                        .name("rest")
+                        .unwrap()
+                        .as_str()
+                        .trim_start_matches(['：', ':']),
+                };
+                return Ok(Some(ParsedRubyTxtElement::EditorialNote {
+                    target,
+                    note: note.to_owned(),
+                }));
+            }
 
             static REGEX_BOU_DECORATION: Lazy<Regex> =
                 Lazy::new(|| Regex::new(r"」(?P<left>の左)?に(?P<style>.*(点|線))$").unwrap());
@@ -166,7 +202,9 @@ pub(super) fn parse_annotation<'a>(
                 };
                 let style = match bou_decoration_style_of(caps.name("style").unwrap().as_str()) {
                     Ok(style) => style,
-                    Err(_) => return Ok(Some(ParsedRubyTxtElement::UnknownAnnotation { args })),
+                    Err(_) => {
+                        return Ok(Some(ParsedRubyTxtElement::UnknownAnnotation { raw, args }))
+                    }
                 };
 
                 return Ok(Some(ParsedRubyTxtElement::BouDecoration {
@@ -176,28 +214,34 @@ pub(super) fn parse_annotation<'a>(
                 }));
             }
 
-            if annotation_name == "は太字" {
+            if annotation_name == "」は太字" {
                 return Ok(Some(ParsedRubyTxtElement::StringDecoration {
                     target,
                     style: StringDecorationStyle::Bold,
                 }));
             }
 
-            if annotation_name == "は斜体" {
+            if annotation_name == "」は斜体" {
                 return Ok(Some(ParsedRubyTxtElement::StringDecoration {
                     target,
                     style: StringDecorationStyle::Italic,
                 }));
             }
 
-            if annotation_name == "はキャプション" {
+            if annotation_name == "」はキャプション" {
                 return Ok(Some(ParsedRubyTxtElement::Caption { value: target }));
             }
+
+            if let Some(name) = annotation_name.strip_prefix("」は") {
+                if let Ok(position) = KogakiPosition::of(name) {
+                    return Ok(Some(ParsedRubyTxtElement::Kogaki { target, position }));
+                }
+            }
         }
 
         // TODO
         if 1 < args.len() {
-            return Ok(Some(ParsedRubyTxtElement::UnknownAnnotation { args }));
+            return Ok(Some(ParsedRubyTxtElement::UnknownAnnotation { raw, args }));
         }
 
         // 1 文字列のもの
@@ -223,51 +267,57 @@ pub(super) fn parse_annotation<'a>(
             return Ok(Some(ParsedRubyTxtElement::KaidanAttention));
         }
 
-        static REGEX_JISAGE: Lazy<Regex> =
-            Lazy::new(|| Regex::new(r"^(?P<level>[０-９]+)字下げ$").unwrap());
+        static REGEX_JISAGE: Lazy<Regex> = Lazy::new(|| {
+            Regex::new(r"^(?P<level>[0-9０-９一二三四五六七八九十百]+)字下げ$").unwrap()
+        });
         if let Some(caps) = REGEX_JISAGE.captures(&arg) {
-            let level = parse_number(caps.name("level").unwrap().as_str())
-                .with_context(|| format!("Failed to parse {:?}", arg))?;
+            // "十十" など数として読めないものは不明な注記とする
+            let Ok(level) = parse_number_kanji(caps.name("level").unwrap().as_str()) else {
+                return Ok(Some(ParsedRubyTxtElement::UnknownAnnotation { raw, args }));
+            };
             return Ok(Some(ParsedRubyTxtElement::JisageAnnotation { level }));
         }
 
-        static REGEX_JISAGE_START: Lazy<Regex> =
-            Lazy::new(|| Regex::new(r"^ここから(?P<level>[０-９]+)字下げ$").unwrap());
-        if let Some(caps) = REGEX_JISAGE_START.captures(&arg) {
-            let level = parse_number(caps.name("level").unwrap().as_str())
-                .with_context(|| format!("Failed to parse {:?}", arg))?;
-            return Ok(Some(ParsedRubyTxtElement::JisageStartAnnotation { level }));
+        // ここから○○ / ここで○○終わり のうち、組み合わせて書けるもの
+        if let Some(el) = block_directive_of(arg)? {
+            return Ok(Some(el));
         }
 
         static REGEX_JISAGE_WITH_ORIKAESHI_START: Lazy<Regex> = Lazy::new(|| {
             Regex::new(
-                r"^ここから(?P<level0>[０-９]+)字下げ、折り返して(?P<level1>[０-９]+)字下げ$",
+                r"^ここから(?P<level0>[0-9０-９一二三四五六七八九十百]+)字下げ、折り返して(?P<level1>[0-9０-９一二三四五六七八九十百]+)字下げ$",
             )
             .unwrap()
         });
         if let Some(caps) = REGEX_JISAGE_WITH_ORIKAESHI_START.captures(&arg) {
-            let level0 = parse_number(caps.name("level0").unwrap().as_str())
-                .with_context(|| format!("Failed to parse {:?}", arg))?;
-            let level1 = parse_number(caps.name("level1").unwrap().as_str())
-                .with_context(|| format!("Failed to parse {:?}", arg))?;
+            let Ok(level0) = parse_number_kanji(caps.name("level0").unwrap().as_str()) else {
+                return Ok(Some(ParsedRubyTxtElement::UnknownAnnotation { raw, args }));
+            };
+            let Ok(level1) = parse_number_kanji(caps.name("level1").unwrap().as_str()) else {
+                return Ok(Some(ParsedRubyTxtElement::UnknownAnnotation { raw, args }));
+            };
             return Ok(Some(
                 ParsedRubyTxtElement::JisageWithOrikaeshiStartAnnotation { level0, level1 },
             ));
         }
 
         static REGEX_JISAGE_AFTER_TENTSUKI_START: Lazy<Regex> = Lazy::new(|| {
-            Regex::new(r"^ここから改行天付き、折り返して(?P<level>[０-９]+)字下げ$").unwrap()
+            Regex::new(
+                r"^ここから改行天付き、折り返して(?P<level>[0-9０-９一二三四五六七八九十百]+)字下げ$",
+            )
+            .unwrap()
         });
         if let Some(caps) = REGEX_JISAGE_AFTER_TENTSUKI_START.captures(&arg) {
-            let level = parse_number(caps.name("level").unwrap().as_str())
-                .with_context(|| format!("Failed to parse {:?}", arg))?;
+            let Ok(level) = parse_number_kanji(caps.name("level").unwrap().as_str()) else {
+                return Ok(Some(ParsedRubyTxtElement::UnknownAnnotation { raw, args }));
+            };
             return Ok(Some(
                 ParsedRubyTxtElement::JisageAfterTentsukiStartAnnotation { level },
             ));
         }
 
-        if arg == "ここで字下げ終わり" {
-            return Ok(Some(ParsedRubyTxtElement::JisageEndAnnotation));
+        if arg == "改行天付き" {
+            return Ok(Some(ParsedRubyTxtElement::KaigiyoTentsukiAnnotation));
         }
 
         if arg == "地付き" {
@@ -282,19 +332,24 @@ pub(super) fn parse_annotation<'a>(
             return Ok(Some(ParsedRubyTxtElement::JitsukiEndAnnotation));
         }
 
-        static REGEX_JIYOSE: Lazy<Regex> =
-            Lazy::new(|| Regex::new(r"^地から(?P<level>[０-９]+)字上げ$").unwrap());
+        static REGEX_JIYOSE: Lazy<Regex> = Lazy::new(|| {
+            Regex::new(r"^地から(?P<level>[0-9０-９一二三四五六七八九十百]+)字上げ$").unwrap()
+        });
         if let Some(caps) = REGEX_JIYOSE.captures(&arg) {
-            let level = parse_number(caps.name("level").unwrap().as_str())
-                .with_context(|| format!("Failed to parse {:?}", arg))?;
+            let Ok(level) = parse_number_kanji(caps.name("level").unwrap().as_str()) else {
+                return Ok(Some(ParsedRubyTxtElement::UnknownAnnotation { raw, args }));
+            };
             return Ok(Some(ParsedRubyTxtElement::JiyoseAnnotation { level }));
         }
 
-        static REGEX_JIYOSE_START: Lazy<Regex> =
-            Lazy::new(|| Regex::new(r"^ここから地から(?P<level>[０-９]+)字上げ$").unwrap());
+        static REGEX_JIYOSE_START: Lazy<Regex> = Lazy::new(|| {
+            Regex::new(r"^ここから地から(?P<level>[0-9０-９一二三四五六七八九十百]+)字上げ$")
+                .unwrap()
+        });
         if let Some(caps) = REGEX_JIYOSE_START.captures(&arg) {
-            let level = parse_number(caps.name("level").unwrap().as_str())
-                .with_context(|| format!("Failed to parse {:?}", arg))?;
+            let Ok(level) = parse_number_kanji(caps.name("level").unwrap().as_str()) else {
+                return Ok(Some(ParsedRubyTxtElement::UnknownAnnotation { raw, args }));
+            };
             return Ok(Some(ParsedRubyTxtElement::JiyoseStartAnnotation { level }));
         }
 
@@ -405,7 +460,7 @@ pub(super) fn parse_annotation<'a>(
             };
             let style = match bou_decoration_style_of(caps.name("style").unwrap().as_str()) {
                 Ok(style) => style,
-                Err(_) => return Ok(Some(ParsedRubyTxtElement::UnknownAnnotation { args })),
+                Err(_) => return Ok(Some(ParsedRubyTxtElement::UnknownAnnotation { raw, args })),
             };
             return Ok(Some(ParsedRubyTxtElement::BouDecorationStart {
                 style,
@@ -425,7 +480,7 @@ pub(super) fn parse_annotation<'a>(
             };
             let style = match bou_decoration_style_of(caps.name("style").unwrap().as_str()) {
                 Ok(style) => style,
-                Err(_) => return Ok(Some(ParsedRubyTxtElement::UnknownAnnotation { args })),
+                Err(_) => return Ok(Some(ParsedRubyTxtElement::UnknownAnnotation { raw, args })),
             };
             return Ok(Some(ParsedRubyTxtElement::BouDecorationEnd { style, side }));
         }
@@ -454,6 +509,16 @@ pub(super) fn parse_annotation<'a>(
             }));
         }
 
+        if let Ok(position) = KogakiPosition::of(arg) {
+            return Ok(Some(ParsedRubyTxtElement::KogakiStart { position }));
+        }
+
+        if let Some(name) = arg.strip_suffix("終わり") {
+            if let Ok(position) = KogakiPosition::of(name) {
+                return Ok(Some(ParsedRubyTxtElement::KogakiEnd { position }));
+            }
+        }
+
         static REGEX_IMAGE: Lazy<Regex> = Lazy::new(|| {
             Regex::new(
                 r"^(?P<alt>.+)（(?P<path>fig[0-9]+_[0-9]+\.png)(、横[0-9]+×縦[0-9]+)?）入る$",
@@ -474,6 +539,30 @@ pub(super) fn parse_annotation<'a>(
             return Ok(Some(ParsedRubyTxtElement::CaptionEnd));
         }
 
+        static REGEX_FOOTNOTE_REF: Lazy<Regex> =
+            Lazy::new(|| Regex::new(r"^注(?P<id>[0-9０-９]+)$").unwrap());
+        if let Some(caps) = REGEX_FOOTNOTE_REF.captures(arg) {
+            let id = caps.name("id").unwrap().as_str().to_owned();
+            return Ok(Some(ParsedRubyTxtElement::FootnoteRef { id }));
+        }
+
+        if let Some(style) = arg.strip_prefix("文字組み") {
+            let style = style.trim_start_matches(['：', ':', '、']);
+            // 既知の設定 (後ろに値が続くこともある)
+            const KNOWN_MOJIGUMI_STYLES: [&str; 1] = ["行頭の約物の扱い"];
+            if !KNOWN_MOJIGUMI_STYLES.iter().any(|k| style.starts_with(k)) {
+                if let Some(on_warning) = &ctx.options.on_warning {
+                    on_warning(&ParseWarning::UnknownLayoutHint {
+                        kind: "mojigumi".to_owned(),
+                        value: style.to_owned(),
+                    });
+                }
+            }
+            return Ok(Some(ParsedRubyTxtElement::Mojigumi {
+                style: style.to_owned(),
+            }));
+        }
+
         if arg == "割り注" {
             return Ok(Some(ParsedRubyTxtElement::WarichuStart));
         }
@@ -482,10 +571,120 @@ pub(super) fn parse_annotation<'a>(
             return Ok(Some(ParsedRubyTxtElement::WarichuEnd));
         }
 
-        Ok(Some(ParsedRubyTxtElement::UnknownAnnotation { args }))
+        Ok(Some(ParsedRubyTxtElement::UnknownAnnotation { raw, args }))
     })()?;
 
-    Ok((tokens, annotation))
+    // 組み込みの規則で解釈できなかったものは利用者の handler に任せる
+    let annotation = match (annotation, &ctx.options.on_unknown_annotation) {
+        (Some(ParsedRubyTxtElement::UnknownAnnotation { raw, args }), Some(handler)) => {
+            match handler(&args) {
+                Some(el) => Some(el),
+                None => Some(ParsedRubyTxtElement::UnknownAnnotation { raw, args }),
+            }
+        }
+        (annotation, _) => annotation,
+    };
+
+    Ok((
+        tokens,
+        with_source(annotation.into_iter().collect(), source),
+    ))
+}
+
+// keep_source のとき、注記から得られた要素の前に原文を置く
+fn with_source(
+    elements: Vec<ParsedRubyTxtElement>,
+    source: Option<String>,
+) -> Vec<ParsedRubyTxtElement> {
+    match source {
+        Some(raw) if !elements.is_empty() => {
+            let mut ret = vec![ParsedRubyTxtElement::AnnotationSource { raw }];
+            ret.extend(elements);
+            ret
+        }
+        _ => elements,
+    }
+}
+
+// 、で区切って書かれた指示
+// すべてが解釈できるときのみ分ける (ここから３字下げ、折り返して５字下げ などは分けない)
+fn split_directives(value: &str) -> Result<Option<Vec<ParsedRubyTxtElement>>> {
+    if !value.contains('、') {
+        return Ok(None);
+    }
+
+    // ［＃改丁、ページの左右中央］
+    let directives: Option<Vec<_>> = value.split('、').map(page_directive_of).collect();
+    if directives.is_some() {
+        return Ok(directives);
+    }
+
+    // ［＃ここから罫囲み、２字下げ］
+    if let Some(rest) = value.strip_prefix("ここから") {
+        return rest
+            .split('、')
+            .map(|part| block_directive_of(&format!("ここから{}", part)))
+            .collect();
+    }
+
+    // ［＃ここで字下げ、罫囲み終わり］
+    if let Some(rest) = value.strip_prefix("ここで") {
+        if rest.ends_with("終わり") {
+            return rest
+                .split('、')
+                .map(|part| {
+                    let part = part.strip_suffix("終わり").unwrap_or(part);
+                    block_directive_of(&format!("ここで{}終わり", part))
+                })
+                .collect();
+        }
+    }
+
+    Ok(None)
+}
+
+// ブロックの字下げ・字詰め・罫囲みの始まりと終わり
+fn block_directive_of(arg: &str) -> Result<Option<ParsedRubyTxtElement>> {
+    static REGEX_JISAGE_START: Lazy<Regex> = Lazy::new(|| {
+        Regex::new(r"^ここから(?P<level>[0-9０-９一二三四五六七八九十百]+)字下げ$").unwrap()
+    });
+    if let Some(caps) = REGEX_JISAGE_START.captures(arg) {
+        // 数として読めないものは解釈しない
+        let Ok(level) = parse_number_kanji(caps.name("level").unwrap().as_str()) else {
+            return Ok(None);
+        };
+        return Ok(Some(ParsedRubyTxtElement::JisageStartAnnotation { level }));
+    }
+
+    static REGEX_LINE_WIDTH_START: Lazy<Regex> = Lazy::new(|| {
+        Regex::new(r"^ここから(?P<chars>[0-9０-９一二三四五六七八九十百]+)字詰め$").unwrap()
+    });
+    if let Some(caps) = REGEX_LINE_WIDTH_START.captures(arg) {
+        let Ok(chars) = parse_number_kanji(caps.name("chars").unwrap().as_str()) else {
+            return Ok(None);
+        };
+        return Ok(Some(ParsedRubyTxtElement::LineWidthStart { chars }));
+    }
+
+    Ok(match arg {
+        "ここで字下げ終わり" => Some(ParsedRubyTxtElement::JisageEndAnnotation),
+        "ここで字詰め終わり" => Some(ParsedRubyTxtElement::LineWidthEnd),
+        "ここから罫囲み" => Some(ParsedRubyTxtElement::KeigakomiStart),
+        "ここで罫囲み終わり" => Some(ParsedRubyTxtElement::KeigakomiEnd),
+        _ => None,
+    })
+}
+
+// 改丁などのページに関する指示
+fn page_directive_of(arg: &str) -> Option<ParsedRubyTxtElement> {
+    match arg {
+        "改丁" => Some(ParsedRubyTxtElement::KaichoAttention),
+        "改ページ" => Some(ParsedRubyTxtElement::KaipageAttention),
+        "改見開き" => Some(ParsedRubyTxtElement::KaimihirakiAttention),
+        "改段" => Some(ParsedRubyTxtElement::KaidanAttention),
+        "ページの左右中央" => Some(ParsedRubyTxtElement::PageCenterAnnotation),
+        _ => None,
+    }
 }
 
 fn bou_decoration_style_of(name: &str) -> Result<BouDecorationStyle> {
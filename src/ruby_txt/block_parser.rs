@@ -6,13 +6,43 @@ use crate::ruby_txt::{
         parse_gaiji_accent_decomposition, ParsedGaijiAccentDecomposition,
     },
     gaiji_annotation_parser::{parse_gaiji_annotation, ParsedGaijiAnnotation},
-    parser::ParsedRubyTxtElement,
+    parser::{ParseOptions, ParsedRubyTxtElement},
     parser_helper::ParsedRubyTxtElementList,
     ruby_parser::parse_ruby,
     tokenizer::RubyTxtToken,
 };
 
-pub(super) fn parse_block<'a>(tokens: &'a [&'a RubyTxtToken]) -> Result<Vec<ParsedRubyTxtElement>> {
+// 入れ子になった注記・ルビなどを解析するときの状態
+#[derive(Clone, Copy)]
+pub(super) struct BlockContext<'o> {
+    pub(super) options: &'o ParseOptions,
+    depth: usize,
+}
+
+impl<'o> BlockContext<'o> {
+    pub(super) fn new(options: &'o ParseOptions) -> Self {
+        Self { options, depth: 0 }
+    }
+
+    // 一段深い入れ子
+    // 再帰が深くなりすぎるとスタックが溢れるので上限を設ける
+    pub(super) fn nested(&self) -> Result<Self> {
+        ensure!(
+            self.depth < self.options.max_nesting_depth,
+            "Too deeply nested (limit: {})",
+            self.options.max_nesting_depth
+        );
+        Ok(Self {
+            options: self.options,
+            depth: self.depth + 1,
+        })
+    }
+}
+
+pub(super) fn parse_block<'a>(
+    tokens: &'a [&'a RubyTxtToken],
+    ctx: &BlockContext,
+) -> Result<Vec<ParsedRubyTxtElement>> {
     let mut tokens = tokens;
     let mut elements = ParsedRubyTxtElementList::new();
 
@@ -40,7 +70,7 @@ pub(super) fn parse_block<'a>(tokens: &'a [&'a RubyTxtToken]) -> Result<Vec<Pars
 
             RubyTxtToken::RubyStart => {
                 // PositionStartDelimiter なしルビ
-                let ruby = parse_ruby(tokens)?;
+                let ruby = parse_ruby(tokens, ctx)?;
 
                 tokens = ruby.0;
 
@@ -59,9 +89,9 @@ pub(super) fn parse_block<'a>(tokens: &'a [&'a RubyTxtToken]) -> Result<Vec<Pars
             }
 
             RubyTxtToken::AnnotationStart => {
-                let parsed = parse_annotation(tokens)?;
+                let parsed = parse_annotation(tokens, ctx)?;
                 tokens = parsed.0;
-                if let Some(el) = parsed.1 {
+                for el in parsed.1 {
                     elements.push(el);
                 }
             }
@@ -73,7 +103,7 @@ pub(super) fn parse_block<'a>(tokens: &'a [&'a RubyTxtToken]) -> Result<Vec<Pars
             }
 
             RubyTxtToken::GaijiAnnotationStart => {
-                let gaiji = parse_gaiji_annotation(tokens)?;
+                let gaiji = parse_gaiji_annotation(tokens, ctx)?;
                 tokens = gaiji.0;
                 let gaiji = gaiji.1;
                 match gaiji {
@@ -90,7 +120,7 @@ pub(super) fn parse_block<'a>(tokens: &'a [&'a RubyTxtToken]) -> Result<Vec<Pars
             }
 
             RubyTxtToken::GaijiAccentDecompositionStart => {
-                match parse_gaiji_accent_decomposition(tokens)? {
+                match parse_gaiji_accent_decomposition(tokens, ctx)? {
                     ParsedGaijiAccentDecomposition::NotAccentDecomposition => {
                         tokens = &tokens[1..];
                         elements.push_char('〔');
@@ -0,0 +1,120 @@
+use crate::ruby_txt::{
+    parser::{ParsedRubyTxt, ParsedRubyTxtElement},
+    utility::{BouDecorationSide, BouDecorationStyle, StringDecorationStyle},
+};
+
+// 傍点・傍線の範囲
+// body の要素の index で [start_index, end_index) を表す
+#[derive(Debug, Clone, PartialEq)]
+pub struct BouDecorationSpan {
+    pub start_index: usize,
+    pub end_index: usize,
+    pub style: BouDecorationStyle,
+    pub side: BouDecorationSide,
+}
+
+// 太字・斜体の範囲
+// body の要素の index で [start_index, end_index) を表す
+#[derive(Debug, Clone, PartialEq)]
+pub struct StringDecorationSpan {
+    pub start_index: usize,
+    pub end_index: usize,
+    pub style: StringDecorationStyle,
+}
+
+// body 中の太字・斜体の範囲をすべて求める
+// - ［＃「○○」は太字］ 等はその要素のみ
+// - ［＃太字］...［＃太字終わり］ 等は間の要素（始まりと終わりの注記は含まない）
+// 対応の取れない始まり・終わりは無視する
+pub fn all_string_decoration_spans(parsed: &ParsedRubyTxt) -> Vec<StringDecorationSpan> {
+    let mut spans = Vec::new();
+
+    // 終わりが見つかっていない始まり
+    let mut starts: Vec<(usize, &StringDecorationStyle)> = Vec::new();
+
+    for (i, element) in parsed.body.iter().enumerate() {
+        match element {
+            ParsedRubyTxtElement::StringDecoration { target: _, style } => {
+                spans.push(StringDecorationSpan {
+                    start_index: i,
+                    end_index: i + 1,
+                    style: style.clone(),
+                });
+            }
+
+            ParsedRubyTxtElement::StringDecorationStart { style } => {
+                starts.push((i, style));
+            }
+
+            ParsedRubyTxtElement::StringDecorationEnd { style } => {
+                if let Some(j) = starts.iter().rposition(|(_, s)| s == &style) {
+                    let (start_index, _) = starts.remove(j);
+                    spans.push(StringDecorationSpan {
+                        start_index: start_index + 1,
+                        end_index: i,
+                        style: style.clone(),
+                    });
+                }
+            }
+
+            _ => {}
+        }
+    }
+
+    spans.sort_by_key(|span| (span.start_index, span.end_index));
+
+    spans
+}
+
+// body 中の傍点・傍線の範囲をすべて求める
+// - ［＃「○○」に傍点］ 等はその要素のみ
+// - ［＃傍点］...［＃傍点終わり］ 等は間の要素（始まりと終わりの注記は含まない）
+// 対応の取れない始まり・終わりは無視する
+pub fn all_bou_decoration_spans(parsed: &ParsedRubyTxt) -> Vec<BouDecorationSpan> {
+    let mut spans = Vec::new();
+
+    // 終わりが見つかっていない始まり
+    let mut starts: Vec<(usize, &BouDecorationStyle, &BouDecorationSide)> = Vec::new();
+
+    for (i, element) in parsed.body.iter().enumerate() {
+        match element {
+            ParsedRubyTxtElement::BouDecoration {
+                target: _,
+                style,
+                side,
+            } => {
+                spans.push(BouDecorationSpan {
+                    start_index: i,
+                    end_index: i + 1,
+                    style: style.clone(),
+                    side: side.clone(),
+                });
+            }
+
+            ParsedRubyTxtElement::BouDecorationStart { style, side } => {
+                starts.push((i, style, side));
+            }
+
+            ParsedRubyTxtElement::BouDecorationEnd { style, side } => {
+                if let Some(j) = starts
+                    .iter()
+                    .rposition(|(_, st, si)| st == &style && si == &side)
+                {
+                    let (start_index, _, _) = starts.remove(j);
+                    spans.push(BouDecorationSpan {
+                        start_index: start_index + 1,
+                        end_index: i,
+                        style: style.clone(),
+                        side: side.clone(),
+                    });
+                }
+            }
+
+            _ => {}
+        }
+    }
+
+    spans.sort_by_key(|span| (span.start_index, span.end_index));
+
+    spans
+}
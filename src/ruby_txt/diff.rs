@@ -0,0 +1,97 @@
+use crate::ruby_txt::parser::{ParsedRubyTxt, ParsedRubyTxtElement};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiffItem {
+    Same(ParsedRubyTxtElement),
+    OnlyInA(ParsedRubyTxtElement),
+    OnlyInB(ParsedRubyTxtElement),
+}
+
+// 2 つの ParsedRubyTxt の body の差分を求める
+// パース結果の形式を変えたときなどに同じ作品の新旧を比べるためのもの
+pub fn diff_parsed_body(a: &ParsedRubyTxt, b: &ParsedRubyTxt) -> Vec<DiffItem> {
+    diff_elements(&a.body, &b.body)
+}
+
+// Myers の差分アルゴリズム
+// http://www.xmailserver.org/diff2.pdf
+fn diff_elements(a: &[ParsedRubyTxtElement], b: &[ParsedRubyTxtElement]) -> Vec<DiffItem> {
+    let n = a.len() as isize;
+    let m = b.len() as isize;
+    let max = n + m;
+
+    // v[offset + k] は対角線 k 上で到達できる最も遠い x
+    let offset = max + 1;
+    let mut v = vec![0; (2 * max + 3) as usize];
+
+    // 各 d での v を記録し、後から経路を復元する
+    // d のときに参照するのは対角線 -d-1..=d+1 のみなので、その範囲だけを残す
+    let mut trace = Vec::new();
+
+    'search: for d in 0..=max {
+        trace.push(v[((offset - d - 1) as usize)..=((offset + d + 1) as usize)].to_vec());
+
+        for k in (-d..=d).step_by(2) {
+            let mut x = if k == -d
+                || (k != d && v[(offset + k - 1) as usize] < v[(offset + k + 1) as usize])
+            {
+                v[(offset + k + 1) as usize]
+            } else {
+                v[(offset + k - 1) as usize] + 1
+            };
+            let mut y = x - k;
+
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+
+            v[(offset + k) as usize] = x;
+
+            if n <= x && m <= y {
+                break 'search;
+            }
+        }
+    }
+
+    let mut items = Vec::new();
+
+    let mut x = n;
+    let mut y = m;
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let k = x - y;
+
+        // v[i] は対角線 i - d - 1
+        let at = |k: isize| v[(k + d + 1) as usize];
+
+        let prev_k = if k == -d || (k != d && at(k - 1) < at(k + 1)) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = at(prev_k);
+        let prev_y = prev_x - prev_k;
+
+        while prev_x < x && prev_y < y {
+            items.push(DiffItem::Same(a[(x - 1) as usize].clone()));
+            x -= 1;
+            y -= 1;
+        }
+
+        if 0 < d {
+            if x == prev_x {
+                items.push(DiffItem::OnlyInB(b[(y - 1) as usize].clone()));
+            } else {
+                items.push(DiffItem::OnlyInA(a[(x - 1) as usize].clone()));
+            }
+        }
+
+        x = prev_x;
+        y = prev_y;
+    }
+
+    items.reverse();
+
+    items
+}
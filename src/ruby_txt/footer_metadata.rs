@@ -0,0 +1,58 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::Serialize;
+
+use crate::ruby_txt::parser::{ParsedRubyTxt, ParsedRubyTxtElement};
+
+// 末尾に書かれた底本・入力者・校正者
+// 見つからなかったものは None
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FooterMetadata {
+    pub original_book_title: Option<String>, // 底本名 (「」や『』の中)
+    pub inputter_name: Option<String>,       // 入力者名
+    pub proofreader_name: Option<String>,    // 校正者名
+}
+
+// 末尾の "底本：", "入力：", "校正：" で始まる行を読む
+// 底本が複数あるときは最初のものとする
+pub fn parse_footer_metadata(parsed: &ParsedRubyTxt) -> FooterMetadata {
+    static REGEX_TEIHON: Lazy<Regex> = Lazy::new(|| {
+        Regex::new(r"^底本(・初出)?[：:][^「『]*[「『](?P<title>[^」』]+)[」』]").unwrap()
+    });
+    static REGEX_INPUTTER: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r"^入力[：:](?P<name>.+)$").unwrap());
+    static REGEX_PROOFREADER: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r"^校正[：:](?P<name>.+)$").unwrap());
+
+    let mut metadata = FooterMetadata {
+        original_book_title: None,
+        inputter_name: None,
+        proofreader_name: None,
+    };
+
+    for line in parsed
+        .footer
+        .split(|e| matches!(e, ParsedRubyTxtElement::NewLine))
+    {
+        let line = line.iter().map(|e| e.text()).collect::<String>();
+
+        let captured = |regex: &Regex, name: &str| {
+            regex
+                .captures(&line)
+                .map(|caps| caps.name(name).unwrap().as_str().trim().to_owned())
+        };
+
+        if metadata.original_book_title.is_none() {
+            metadata.original_book_title = captured(&REGEX_TEIHON, "title");
+        }
+        if metadata.inputter_name.is_none() {
+            metadata.inputter_name = captured(&REGEX_INPUTTER, "name");
+        }
+        if metadata.proofreader_name.is_none() {
+            metadata.proofreader_name = captured(&REGEX_PROOFREADER, "name");
+        }
+    }
+
+    metadata
+}
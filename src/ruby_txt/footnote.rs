@@ -0,0 +1,75 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+use crate::{
+    ruby_txt::parser::{ParsedRubyTxt, ParsedRubyTxtElement},
+    utility::str::parse_number,
+};
+
+// 本文中の注の参照 ［＃注○］
+// index は body の要素の index
+#[derive(Debug, Clone, PartialEq)]
+pub struct FootnoteRef {
+    pub index: usize,
+    pub id: String,
+}
+
+// 本文中の注の参照と、末尾にある対応する注の内容の組をすべて求める
+// 末尾の注は "注○　●●" や "（注○）●●" のように行頭に番号を書いたものとし、その行の残りを内容とする
+// 対応する注が末尾にない参照は含めない
+pub fn resolve_footnotes(parsed: &ParsedRubyTxt) -> Vec<(FootnoteRef, Vec<ParsedRubyTxtElement>)> {
+    let notes = footnotes_in_footer(&parsed.footer);
+
+    let mut ret = Vec::new();
+    for (index, element) in parsed.body.iter().enumerate() {
+        let ParsedRubyTxtElement::FootnoteRef { id } = element else {
+            continue;
+        };
+        let Ok(number) = parse_number(id) else {
+            continue;
+        };
+
+        if let Some((_, note)) = notes.iter().find(|(n, _)| n == &number) {
+            ret.push((
+                FootnoteRef {
+                    index,
+                    id: id.clone(),
+                },
+                note.clone(),
+            ));
+        }
+    }
+    ret
+}
+
+// 末尾の注 (番号, 内容)
+fn footnotes_in_footer(footer: &[ParsedRubyTxtElement]) -> Vec<(usize, Vec<ParsedRubyTxtElement>)> {
+    static REGEX_FOOTNOTE: Lazy<Regex> = Lazy::new(|| {
+        Regex::new(r"^[（(]?注(?P<id>[0-9０-９]+)[）)]?[　 ：:]*(?P<rest>.*)$").unwrap()
+    });
+
+    let mut notes = Vec::new();
+    for line in footer.split(|e| matches!(e, ParsedRubyTxtElement::NewLine)) {
+        let Some(ParsedRubyTxtElement::String { value }) = line.first() else {
+            continue;
+        };
+        let Some(caps) = REGEX_FOOTNOTE.captures(value) else {
+            continue;
+        };
+        let Ok(number) = parse_number(caps.name("id").unwrap().as_str()) else {
+            continue;
+        };
+
+        let mut note = Vec::new();
+        let rest = caps.name("rest").unwrap().as_str();
+        if !rest.is_empty() {
+            note.push(ParsedRubyTxtElement::String {
+                value: rest.to_owned(),
+            });
+        }
+        note.extend(line[1..].iter().cloned());
+
+        notes.push((number, note));
+    }
+    notes
+}
@@ -2,7 +2,10 @@ use anyhow::{ensure, Result};
 
 use crate::{
     ruby_txt::parser::ParsedRubyTxtElement,
-    ruby_txt::{block_parser::parse_block, tokenizer::RubyTxtToken},
+    ruby_txt::{
+        block_parser::{parse_block, BlockContext},
+        tokenizer::RubyTxtToken,
+    },
 };
 
 pub(super) enum ParsedGaijiAccentDecomposition<'a> {
@@ -13,6 +16,7 @@ pub(super) enum ParsedGaijiAccentDecomposition<'a> {
 // GaijiAccentDecompositionStart String GaijiAccentDecompositionEnd
 pub(super) fn parse_gaiji_accent_decomposition<'a>(
     tokens: &'a [&'a RubyTxtToken],
+    ctx: &BlockContext,
 ) -> Result<ParsedGaijiAccentDecomposition<'a>> {
     ensure!(matches!(
         tokens.get(0),
@@ -69,7 +73,7 @@ pub(super) fn parse_gaiji_accent_decomposition<'a>(
     }
 
     let processed_tokens = processed_tokens.iter().map(|t| t).collect::<Vec<_>>();
-    let child_elements = parse_block(&processed_tokens)?;
+    let child_elements = parse_block(&processed_tokens, &ctx.nested()?)?;
 
     Ok(ParsedGaijiAccentDecomposition::Composed(
         &tokens[(end_index + 1)..],
@@ -3,7 +3,11 @@ use once_cell::sync::Lazy;
 use regex::Regex;
 
 use crate::{
-    ruby_txt::{block_parser::parse_block, parser::ParsedRubyTxtElement, tokenizer::RubyTxtToken},
+    ruby_txt::{
+        block_parser::{parse_block, BlockContext},
+        parser::ParsedRubyTxtElement,
+        tokenizer::RubyTxtToken,
+    },
     utility::jis_x_0213::JIS_X_0213,
 };
 
@@ -15,6 +19,7 @@ pub(super) enum ParsedGaijiAnnotation {
 // GaijiAnnotationStart String AnnotationEnd
 pub(super) fn parse_gaiji_annotation<'a>(
     tokens: &'a [&'a RubyTxtToken],
+    ctx: &BlockContext,
 ) -> Result<(&'a [&'a RubyTxtToken], ParsedGaijiAnnotation)> {
     ensure!(matches!(
         tokens.get(0),
@@ -51,7 +56,7 @@ pub(super) fn parse_gaiji_annotation<'a>(
     let child_tokens = &tokens[..end_index];
     let tokens = &tokens[(end_index + 1)..];
 
-    let child_elements = parse_block(&child_tokens)?;
+    let child_elements = parse_block(&child_tokens, &ctx.nested()?)?;
     ensure!(
         child_elements.len() == 1,
         "Invalid gaiji annotation: {:?}",
@@ -103,8 +108,9 @@ pub(super) fn parse_gaiji_annotation<'a>(
     }
 
     // 外字（第 1 第 2 水準にない漢字：JIS X 0213 にないが Unicode にある，特殊な仮名や記号など）
+    // 面区点番号 (N-N) が続かないものや、後ろに説明が続くものもある
     static REGEX_UNICODE: Lazy<Regex> =
-        Lazy::new(|| Regex::new(r"^.+?、U\+(?P<unicode>[0-9A-Fa-f]+)、[0-9]+-[0-9]+$").unwrap());
+        Lazy::new(|| Regex::new(r"^.+?、U\+(?P<unicode>[0-9A-Fa-f]{4,6})(、.*)?$").unwrap());
     if let Some(caps) = REGEX_UNICODE.captures(&annotation) {
         let unicode = caps.name("unicode").unwrap().as_str();
         let unicode = u32::from_str_radix(unicode, 16).context("Invalid unicode")?;
@@ -113,6 +119,56 @@ pub(super) fn parse_gaiji_annotation<'a>(
         return Ok((tokens, ParsedGaijiAnnotation::String(char.to_string())));
     }
 
+    // 濁点・半濁点付きの仮名（表になければ仮名に結合文字を付ける）
+    static REGEX_KANA_WITH_MARK: Lazy<Regex> = Lazy::new(|| {
+        Regex::new(r"^(?P<mark>半濁点|濁点)付き(平仮名|片仮名)(?P<kana>[ぁ-ゟ゠-ヿ])(、.*)?$")
+            .unwrap()
+    });
+    if let Some(caps) = REGEX_KANA_WITH_MARK.captures(annotation) {
+        let kana = caps.name("kana").unwrap().as_str().chars().next().unwrap();
+        let handakuten = caps.name("mark").unwrap().as_str() == "半濁点";
+        return Ok((
+            tokens,
+            ParsedGaijiAnnotation::String(compose_kana_mark(kana, handakuten)),
+        ));
+    }
+
     // TODO
     Ok((tokens, ParsedGaijiAnnotation::Unknown(annotation.clone())))
 }
+
+// 仮名に濁点・半濁点を付ける
+// 合成済みの文字があればそれにし (NFC と同じ)、なければ結合文字 (U+3099, U+309A) を続ける
+fn compose_kana_mark(kana: char, handakuten: bool) -> String {
+    let composed = if handakuten {
+        "はひふへほハヒフヘホ"
+            .contains(kana)
+            .then(|| char::from_u32(kana as u32 + 2))
+            .flatten()
+    } else if "かきくけこさしすせそたちつてとはひふへほカキクケコサシスセソタチツテトハヒフヘホ"
+        .contains(kana)
+    {
+        char::from_u32(kana as u32 + 1)
+    } else {
+        match kana {
+            'う' => Some('ゔ'),
+            'ウ' => Some('ヴ'),
+            'ワ' => Some('ヷ'),
+            'ヰ' => Some('ヸ'),
+            'ヱ' => Some('ヹ'),
+            'ヲ' => Some('ヺ'),
+            'ゝ' => Some('ゞ'),
+            'ヽ' => Some('ヾ'),
+            _ => None,
+        }
+    };
+
+    match composed {
+        Some(c) => c.to_string(),
+        None => format!(
+            "{}{}",
+            kana,
+            if handakuten { '\u{309A}' } else { '\u{3099}' }
+        ),
+    }
+}
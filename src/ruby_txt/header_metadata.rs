@@ -0,0 +1,80 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::Serialize;
+
+use crate::ruby_txt::parser::{ParsedRubyTxt, ParsedRubyTxtElement};
+
+// 冒頭に書かれた作品名・副題・著者
+// CSV がないときの手がかりなので推測による
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HeaderMeta {
+    pub title: Option<String>,      // 作品名
+    pub subtitle: Option<String>,   // 副題
+    pub author: Option<String>,     // 著者名
+    pub translator: Option<String>, // 訳者・編者など (「○○訳」の行)
+}
+
+// 冒頭は "作品名 [副題...] 著者名 [訳者名]" の順に 1 行ずつ
+// 古い形式では冒頭が作品名のみで、著者名は空行を挟んだ次の行にある
+pub fn extract_header_meta(parsed: &ParsedRubyTxt) -> HeaderMeta {
+    static REGEX_TRANSLATOR: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r"(訳|譯|編|編訳|訳編|校訂|校注|補)$").unwrap());
+
+    let mut lines: Vec<String> = lines_of(&parsed.header)
+        .filter(|line| !line.is_empty())
+        .collect();
+
+    let mut metadata = HeaderMeta {
+        title: None,
+        subtitle: None,
+        author: None,
+        translator: None,
+    };
+
+    if lines.is_empty() {
+        return metadata;
+    }
+    metadata.title = Some(lines.remove(0));
+
+    if 1 < lines.len() && REGEX_TRANSLATOR.is_match(lines.last().unwrap()) {
+        metadata.translator = lines.pop();
+    }
+
+    match lines.len() {
+        0 => metadata.author = old_style_author(&parsed.body),
+        1 => metadata.author = lines.pop(),
+        _ => {
+            metadata.author = lines.pop();
+            metadata.subtitle = Some(lines.join("　"));
+        }
+    }
+
+    metadata
+}
+
+// 本文の最初の段落が短い 1 行だけなら著者名とみなす
+fn old_style_author(body: &[ParsedRubyTxtElement]) -> Option<String> {
+    let mut lines = lines_of(body);
+
+    let first = lines.next()?;
+    let followed_by_blank = lines.next().is_some_and(|line| line.is_empty());
+
+    let is_name = !first.is_empty()
+        && first.chars().count() <= 20
+        && !first.contains(['。', '、', '「', '」']);
+    (followed_by_blank && is_name).then_some(first)
+}
+
+// 前後の空白を除いた各行
+fn lines_of(elements: &[ParsedRubyTxtElement]) -> impl Iterator<Item = String> + '_ {
+    elements
+        .split(|e| matches!(e, ParsedRubyTxtElement::NewLine))
+        .map(|line| {
+            line.iter()
+                .map(|e| e.text())
+                .collect::<String>()
+                .trim_matches(&[' ', '　'][..])
+                .to_owned()
+        })
+}
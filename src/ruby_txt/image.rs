@@ -0,0 +1,30 @@
+use crate::ruby_txt::parser::{ParsedRubyTxt, ParsedRubyTxtElement};
+
+// 冒頭・本文・末尾にある画像 (path, alt) をすべて求める
+// 傍点・太字などの対象やキャプションの中も探す
+pub fn collect_all_images(parsed: &ParsedRubyTxt) -> Vec<(&str, &str)> {
+    let mut images = Vec::new();
+    for elements in [&parsed.header, &parsed.body, &parsed.footer] {
+        collect_images(elements, &mut images);
+    }
+    images
+}
+
+fn collect_images<'a>(elements: &'a [ParsedRubyTxtElement], images: &mut Vec<(&'a str, &'a str)>) {
+    for element in elements {
+        match element {
+            ParsedRubyTxtElement::Image { path, alt } => images.push((path, alt)),
+
+            ParsedRubyTxtElement::BouDecoration { target, .. }
+            | ParsedRubyTxtElement::StringDecoration { target, .. }
+            | ParsedRubyTxtElement::Kogaki { target, .. }
+            | ParsedRubyTxtElement::EditorialNote { target, .. } => collect_images(target, images),
+            ParsedRubyTxtElement::Caption { value } | ParsedRubyTxtElement::Ruby { value } => {
+                collect_images(value, images)
+            }
+            ParsedRubyTxtElement::UnknownAnnotation { args, .. } => collect_images(args, images),
+
+            _ => {}
+        }
+    }
+}
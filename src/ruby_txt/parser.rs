@@ -1,13 +1,16 @@
+use std::{fmt, sync::Arc};
+
 use anyhow::{ensure, Context, Result};
 use once_cell::sync::Lazy;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 
 use crate::ruby_txt::{
-    block_parser::parse_block,
+    block_parser::{parse_block, BlockContext},
     tokenizer::RubyTxtToken,
     utility::{
-        BouDecorationSide, BouDecorationStyle, MidashiLevel, MidashiStyle, StringDecorationStyle,
+        BouDecorationSide, BouDecorationStyle, KogakiPosition, MidashiLevel, MidashiStyle,
+        StringDecorationStyle,
     },
 };
 
@@ -19,7 +22,29 @@ pub struct ParsedRubyTxt {
     pub footer: Vec<ParsedRubyTxtElement>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl ParsedRubyTxt {
+    // 本文に改丁・改ページ・改見開き・改段があるか
+    pub fn has_section_break(&self) -> bool {
+        self.body.iter().any(is_section_break)
+    }
+
+    // 改丁・改ページ・改見開き・改段で区切られた本文の区画の数
+    pub fn section_count(&self) -> usize {
+        self.body.iter().filter(|el| is_section_break(el)).count() + 1
+    }
+}
+
+fn is_section_break(el: &ParsedRubyTxtElement) -> bool {
+    matches!(
+        el,
+        ParsedRubyTxtElement::KaichoAttention
+            | ParsedRubyTxtElement::KaipageAttention
+            | ParsedRubyTxtElement::KaimihirakiAttention
+            | ParsedRubyTxtElement::KaidanAttention
+    )
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case", tag = "type")]
 pub enum ParsedRubyTxtElement {
     String {
@@ -27,10 +52,28 @@ pub enum ParsedRubyTxtElement {
     },
     NewLine,
     UnknownAnnotation {
+        // ［＃ ］ の中身そのまま
+        raw: String,
         // 非空
         args: Vec<ParsedRubyTxtElement>,
     },
 
+    // ［＃］
+    // "［＃］：入力者注" のように、入力者注の印として使われる
+    EmptyAnnotation,
+
+    // 注記の原文 ［＃○○］ (ParseOptions::keep_source のときのみ)
+    // 直後にその注記から得られた要素が続く
+    AnnotationSource {
+        raw: String,
+    },
+
+    // 元の .txt で、次の要素から始まる行の行番号 (1 始まり) (ParseOptions::track_lines のときのみ)
+    // 各行の先頭に置く
+    SourceLine {
+        line: usize,
+    },
+
     // ｜
     PositionMarker,
 
@@ -62,9 +105,18 @@ pub enum ParsedRubyTxtElement {
     JisageAfterTentsukiStartAnnotation {
         level: usize,
     },
+    // ［＃改行天付き］
+    KaigiyoTentsukiAnnotation,
     // ［＃ここで字下げ終わり］
     JisageEndAnnotation,
 
+    // ［＃ここから○字詰め］ => { chars: ○ }
+    LineWidthStart {
+        chars: usize,
+    },
+    // ［＃ここで字詰め終わり］
+    LineWidthEnd,
+
     // ［＃地付き］
     JitsukiAnnotation,
     // ［＃ここから地付き］
@@ -86,6 +138,11 @@ pub enum ParsedRubyTxtElement {
     // ［＃ページの左右中央］
     PageCenterAnnotation,
 
+    // ［＃ここから罫囲み］
+    KeigakomiStart,
+    // ［＃ここで罫囲み終わり］
+    KeigakomiEnd,
+
     // 見出し
     Midashi {
         value: String,
@@ -141,6 +198,21 @@ pub enum ParsedRubyTxtElement {
         style: StringDecorationStyle,
     },
 
+    // 小書き
+    // ［＃「○○」は行右小書き］ 等
+    Kogaki {
+        target: Vec<ParsedRubyTxtElement>,
+        position: KogakiPosition,
+    },
+    // ［＃行右小書き］ 等
+    KogakiStart {
+        position: KogakiPosition,
+    },
+    // ［＃行右小書き終わり］ 等
+    KogakiEnd {
+        position: KogakiPosition,
+    },
+
     // ［＃○○（●●.png）入る］
     Image {
         path: String,
@@ -155,48 +227,261 @@ pub enum ParsedRubyTxtElement {
     // ［＃キャプション終わり］
     CaptionEnd,
 
+    // ［＃「○○」に「●●」の注記］ => { target: ○○, note: ●● }
+    // ［＃「○○」の注記：●●］ => { target: ○○, note: ●● }
+    EditorialNote {
+        target: Vec<ParsedRubyTxtElement>,
+        note: String,
+    },
+
+    // ［＃注○］ => { id: ○ }
+    FootnoteRef {
+        id: String,
+    },
+
+    // ［＃文字組み○○］ => { style: ○○ }
+    Mojigumi {
+        style: String,
+    },
+
     // ［＃割り注］
     WarichuStart,
     // ［＃割り注終わり］
     WarichuEnd,
 }
 
-// 構文解析
-pub fn parse_ruby_txt(tokens: &[RubyTxtToken]) -> Result<ParsedRubyTxt> {
-    ensure!(!tokens.is_empty(), "Cannot parse empty array");
-
-    let mut tokens = tokens;
-
-    // 冒頭
-    let header = {
-        ensure!(
-            !matches!(tokens[0], RubyTxtToken::NewLine),
-            "Header starts with empty line"
-        );
+impl ParsedRubyTxtElement {
+    // 本文として表示される文字列
+    // ルビの読みや注記の対象（「○○」の部分）は直前の文字列と重複するので含めない
+    pub fn text(&self) -> String {
+        match &self {
+            Self::String { value } => value.clone(),
+            Self::NewLine => "\n".to_owned(),
+            Self::KuntenOkurigana { value } => value.clone(),
+            _ => "".to_owned(),
+        }
+    }
 
-        let mut header_tokens = Vec::new();
+    // JSON の "type" と同じ名前
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::String { .. } => "string",
+            Self::NewLine => "new-line",
+            Self::UnknownAnnotation { .. } => "unknown-annotation",
+            Self::EmptyAnnotation => "empty-annotation",
+            Self::AnnotationSource { .. } => "annotation-source",
+            Self::SourceLine { .. } => "source-line",
+            Self::PositionMarker => "position-marker",
+            Self::Ruby { .. } => "ruby",
+            Self::KaichoAttention => "kaicho-attention",
+            Self::KaipageAttention => "kaipage-attention",
+            Self::KaimihirakiAttention => "kaimihiraki-attention",
+            Self::KaidanAttention => "kaidan-attention",
+            Self::JisageAnnotation { .. } => "jisage-annotation",
+            Self::JisageStartAnnotation { .. } => "jisage-start-annotation",
+            Self::JisageWithOrikaeshiStartAnnotation { .. } => {
+                "jisage-with-orikaeshi-start-annotation"
+            }
+            Self::JisageAfterTentsukiStartAnnotation { .. } => {
+                "jisage-after-tentsuki-start-annotation"
+            }
+            Self::KaigiyoTentsukiAnnotation => "kaigiyo-tentsuki-annotation",
+            Self::JisageEndAnnotation => "jisage-end-annotation",
+            Self::LineWidthStart { .. } => "line-width-start",
+            Self::LineWidthEnd => "line-width-end",
+            Self::JitsukiAnnotation => "jitsuki-annotation",
+            Self::JitsukiStartAnnotation => "jitsuki-start-annotation",
+            Self::JitsukiEndAnnotation => "jitsuki-end-annotation",
+            Self::JiyoseAnnotation { .. } => "jiyose-annotation",
+            Self::JiyoseStartAnnotation { .. } => "jiyose-start-annotation",
+            Self::JiyoseEndAnnotation => "jiyose-end-annotation",
+            Self::PageCenterAnnotation => "page-center-annotation",
+            Self::KeigakomiStart => "keigakomi-start",
+            Self::KeigakomiEnd => "keigakomi-end",
+            Self::Midashi { .. } => "midashi",
+            Self::MidashiStart { .. } => "midashi-start",
+            Self::MidashiEnd => "midashi-end",
+            Self::Kaeriten { .. } => "kaeriten",
+            Self::KuntenOkurigana { .. } => "kunten-okurigana",
+            Self::BouDecoration { .. } => "bou-decoration",
+            Self::BouDecorationStart { .. } => "bou-decoration-start",
+            Self::BouDecorationEnd { .. } => "bou-decoration-end",
+            Self::StringDecoration { .. } => "string-decoration",
+            Self::StringDecorationStart { .. } => "string-decoration-start",
+            Self::StringDecorationEnd { .. } => "string-decoration-end",
+            Self::Kogaki { .. } => "kogaki",
+            Self::KogakiStart { .. } => "kogaki-start",
+            Self::KogakiEnd { .. } => "kogaki-end",
+            Self::Image { .. } => "image",
+            Self::Caption { .. } => "caption",
+            Self::CaptionStart => "caption-start",
+            Self::CaptionEnd => "caption-end",
+            Self::EditorialNote { .. } => "editorial-note",
+            Self::FootnoteRef { .. } => "footnote-ref",
+            Self::Mojigumi { .. } => "mojigumi",
+            Self::WarichuStart => "warichu-start",
+            Self::WarichuEnd => "warichu-end",
+        }
+    }
+}
 
-        loop {
-            let token = tokens.get(0).context("Failed to load header")?;
-            tokens = &tokens[1..];
+// 組み込みの規則で解釈できない注記を解釈する関数
+// 注記の中身 (［＃ ］の間をパースしたもの) を受け取り、None なら UnknownAnnotation のままにする
+pub type UnknownAnnotationHandler =
+    dyn Fn(&[ParsedRubyTxtElement]) -> Option<ParsedRubyTxtElement> + Send + Sync;
+
+// エラーにはせずに補ったものなどを知らせる関数
+pub type WarningHandler = dyn Fn(&ParseWarning) + Send + Sync;
+
+// on_warning で知らせるもの
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseWarning {
+    // ブロックの境で閉じていない注記を閉じた (closer はそのために補った注記)
+    UnclosedBlockAnnotation { closer: ParsedRubyTxtElement },
+    // 文字組みなどの設定の値が未知のもの (要素にはそのまま残す)
+    UnknownLayoutHint { kind: String, value: String },
+}
 
-            if token == &RubyTxtToken::NewLine && tokens.get(0) == Some(&RubyTxtToken::NewLine) {
-                break;
+impl fmt::Display for ParseWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseWarning::UnclosedBlockAnnotation { closer } => write!(
+                f,
+                "Block annotation is not closed at the block boundary: {:?} is inserted",
+                closer
+            ),
+            ParseWarning::UnknownLayoutHint { kind, value } => {
+                write!(f, "Unknown {} style: {:?}", kind, value)
             }
+        }
+    }
+}
 
-            header_tokens.push(token);
+#[derive(Clone)]
+pub struct ParseOptions {
+    // 冒頭と末尾だけを解析し、本文は飛ばす（body は空になる）
+    pub metadata_only: bool,
+    // 注記・ルビなどの入れ子の深さの上限　超えるとエラーにする
+    pub max_nesting_depth: usize,
+    // 本文のブロックの区切りとみなす行を構成する文字
+    pub separator_chars: Vec<char>,
+    // 区切りの行とみなす最小の文字数
+    // "――" など本文中のダッシュだけの行を区切りとみなさないようにする
+    pub separator_min_len: usize,
+    // 行の途中にある改丁・改ページ・改段の前後で行を分ける
+    pub lenient_page_breaks: bool,
+    // 組み込みの規則をすべて試した後、UnknownAnnotation になるものに対してのみ呼ばれる
+    // (「ママ」の注記など、組み込みの規則で無視される注記や、エラーになる注記には呼ばれない)
+    pub on_unknown_annotation: Option<Arc<UnknownAnnotationHandler>>,
+    // 注記の外の全角英数字・記号 (U+FF01〜U+FF5E) を半角にする
+    pub normalize_fullwidth: bool,
+    // 注記から得られた要素の直前に AnnotationSource を置く
+    pub keep_source: bool,
+    // パースの後に整合性を検査し、おかしなものがあればエラーにする
+    pub strict: bool,
+    // 規格から外れているがエラーにせずに処理したものを知らせる
+    pub on_warning: Option<Arc<WarningHandler>>,
+    // 各行の先頭に元の .txt の行番号 (SourceLine) を置く
+    pub track_lines: bool,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            metadata_only: false,
+            max_nesting_depth: 64,
+            separator_chars: vec!['-', '━', '—', '―', '─'],
+            separator_min_len: 10,
+            lenient_page_breaks: false,
+            on_unknown_annotation: None,
+            normalize_fullwidth: false,
+            keep_source: false,
+            strict: false,
+            on_warning: None,
+            track_lines: false,
         }
+    }
+}
 
-        let mut elements = parse_block(&header_tokens)?;
+impl ParseOptions {
+    // tokens[index] が本文のブロックの区切りの行か
+    // 本文中のダッシュと区別するため、その文字列だけで 1 行になっているものに限る
+    fn is_separator(&self, tokens: &[RubyTxtToken], index: usize) -> bool {
+        let RubyTxtToken::String(string) = &tokens[index] else {
+            return false;
+        };
+        let is_line_start = index == 0 || tokens[index - 1] == RubyTxtToken::NewLine;
+        let is_line_end = matches!(tokens.get(index + 1), None | Some(RubyTxtToken::NewLine));
+
+        is_line_start
+            && is_line_end
+            && string.chars().count() >= self.separator_min_len
+            && string.chars().all(|c| self.separator_chars.contains(&c))
+    }
+}
 
-        // 最後の空行を消す
-        while let Some(last) = elements.last() {
-            if !matches!(last, ParsedRubyTxtElement::NewLine) {
-                break;
-            }
-            elements.pop();
+// 関数は中身を表示できないので <fn> とする
+impl fmt::Debug for ParseOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let callback = |c: bool| if c { Some("<fn>") } else { None };
+        f.debug_struct("ParseOptions")
+            .field("metadata_only", &self.metadata_only)
+            .field("max_nesting_depth", &self.max_nesting_depth)
+            .field("separator_chars", &self.separator_chars)
+            .field("separator_min_len", &self.separator_min_len)
+            .field("lenient_page_breaks", &self.lenient_page_breaks)
+            .field(
+                "on_unknown_annotation",
+                &callback(self.on_unknown_annotation.is_some()),
+            )
+            .field("normalize_fullwidth", &self.normalize_fullwidth)
+            .field("keep_source", &self.keep_source)
+            .field("strict", &self.strict)
+            .field("on_warning", &callback(self.on_warning.is_some()))
+            .field("track_lines", &self.track_lines)
+            .finish()
+    }
+}
+
+// 構文解析
+pub fn parse_ruby_txt(tokens: &[RubyTxtToken]) -> Result<ParsedRubyTxt> {
+    parse_ruby_txt_with_options(tokens, &ParseOptions::default())
+}
+
+pub fn parse_ruby_txt_with_options(
+    tokens: &[RubyTxtToken],
+    options: &ParseOptions,
+) -> Result<ParsedRubyTxt> {
+    ensure!(!tokens.is_empty(), "Cannot parse empty array");
+
+    let ctx = BlockContext::new(options);
+
+    let normalized_tokens;
+    let mut tokens = if options.normalize_fullwidth {
+        normalized_tokens = normalize_fullwidth(tokens);
+        &normalized_tokens[..]
+    } else {
+        tokens
+    };
+
+    // 残りの tokens の先頭の行番号
+    let all_tokens = tokens;
+    let line_at = |rest: &[RubyTxtToken]| {
+        all_tokens[..(all_tokens.len() - rest.len())]
+            .iter()
+            .filter(|&t| t == &RubyTxtToken::NewLine)
+            .count()
+            + 1
+    };
+
+    // 冒頭
+    let header = {
+        let (rest, mut elements) = parse_header(tokens, &ctx)?;
+        tokens = rest;
+
+        if options.track_lines {
+            elements = with_source_lines(elements, 1);
         }
-        ensure!(!elements.is_empty(), "Header is empty");
 
         elements
     };
@@ -206,62 +491,61 @@ pub fn parse_ruby_txt(tokens: &[RubyTxtToken]) -> Result<ParsedRubyTxt> {
         tokens = &tokens[1..];
     }
 
-    let body = {
-        // "底本："
-        static REGEX_FOOTER_CHECKER: Lazy<Regex> = Lazy::new(|| Regex::new(r"^底本[：:]").unwrap());
+    let body_line = line_at(tokens);
+    let footer_start = find_footer_start(tokens, options).context("Failed to load body")?;
+    let body_tokens = &tokens[..footer_start];
+    tokens = &tokens[footer_start..];
 
+    let body = if options.metadata_only {
+        // 末尾の始まりまで読み飛ばす
+        Vec::new()
+    } else {
         let mut blocks = vec![vec![]];
-        loop {
-            let token = tokens.get(0).context("Failed to load body")?;
-
-            if let RubyTxtToken::String(string) = token {
-                if REGEX_FOOTER_CHECKER.is_match(&string) {
-                    break;
-                }
-            }
-
-            tokens = &tokens[1..];
-
-            if let RubyTxtToken::String(string) = token {
-                // 主に "【テキスト中に現れる記号について】" を表す区切り
-                // その他にも単なる区切りとして使われることもある（改ページ？）
-                // 個数は一定でない
-                // この区切りで表されるものをブロックと呼ぶ
-                if string.chars().into_iter().all(|c| c == '-') {
-                    if !blocks.last().unwrap().is_empty() {
-                        blocks.push(vec![]);
-                    }
-                    continue;
+        // 各ブロックの先頭の行番号
+        let mut block_lines = vec![body_line];
+        let mut line = body_line;
+        for (i, token) in body_tokens.iter().enumerate() {
+            // 主に "【テキスト中に現れる記号について】" を表す区切り
+            // その他にも単なる区切りとして使われることもある（改ページ？）
+            // 個数は一定でない
+            // '-' の代わりに罫線やダッシュを使うものもある
+            // この区切りで表されるものをブロックと呼ぶ
+            if options.is_separator(body_tokens, i) {
+                if !blocks.last().unwrap().is_empty() {
+                    blocks.push(vec![]);
+                    block_lines.push(line);
+                } else {
+                    *block_lines.last_mut().unwrap() = line;
                 }
+                continue;
             }
 
             blocks.last_mut().unwrap().push(token);
+            if token == &RubyTxtToken::NewLine {
+                line += 1;
+            }
         }
 
         // 長ハイフン (REGEX_ALL_HYPHEN) を footer の区切りにしているものがある
         if blocks.last().unwrap().is_empty() {
             blocks.pop();
+            block_lines.pop();
         }
 
         let mut elements = Vec::new();
 
-        for block in blocks {
-            // ブロックの境は改ページにする
-            if let Some(last) = elements.last() {
-                if !matches!(last, ParsedRubyTxtElement::KaipageAttention) {
-                    if !matches!(last, ParsedRubyTxtElement::NewLine) {
-                        elements.push(ParsedRubyTxtElement::NewLine);
-                    }
-                    elements.push(ParsedRubyTxtElement::KaipageAttention);
-                    elements.push(ParsedRubyTxtElement::NewLine);
-                }
-            }
+        // 閉じていないブロックの範囲の注記の、終わりの注記
+        let mut closers: Vec<ParsedRubyTxtElement> = Vec::new();
 
+        for (block, block_line) in blocks.into_iter().zip(block_lines) {
             // 前後の空行を削除
-            let start_index = block
+            // 空行のみのブロック (末尾の直前の区切りの後など) は飛ばす
+            let Some(start_index) = block
                 .iter()
                 .position(|&token| !matches!(token, RubyTxtToken::NewLine))
-                .context("Empty block is found")?;
+            else {
+                continue;
+            };
             let end_index = block.len()
                 - block
                     .iter()
@@ -280,7 +564,52 @@ pub fn parse_ruby_txt(tokens: &[RubyTxtToken]) -> Result<ParsedRubyTxt> {
                 }
             }
 
-            let sub_elements = parse_block(block)?;
+            // 前のブロックで閉じていないものはブロックの境で閉じる
+            // 次のブロックに字下げなどが持ち越されないようにする
+            if !closers.is_empty() {
+                if !matches!(elements.last(), Some(ParsedRubyTxtElement::NewLine)) {
+                    elements.push(ParsedRubyTxtElement::NewLine);
+                }
+                for closer in closers.drain(..).rev() {
+                    if let Some(on_warning) = &options.on_warning {
+                        on_warning(&ParseWarning::UnclosedBlockAnnotation {
+                            closer: closer.clone(),
+                        });
+                    }
+                    elements.push(closer);
+                    elements.push(ParsedRubyTxtElement::NewLine);
+                }
+            }
+
+            // ブロックの境は改ページにする
+            // 飛ばしたブロックの前には入れない
+            if let Some(last) = elements.last() {
+                if !matches!(last, ParsedRubyTxtElement::KaipageAttention) {
+                    if !matches!(last, ParsedRubyTxtElement::NewLine) {
+                        elements.push(ParsedRubyTxtElement::NewLine);
+                    }
+                    elements.push(ParsedRubyTxtElement::KaipageAttention);
+                    elements.push(ParsedRubyTxtElement::NewLine);
+                }
+            }
+
+            let mut sub_elements = parse_block(block, &ctx)?;
+            if options.track_lines {
+                sub_elements = with_source_lines(sub_elements, block_line + start_index);
+            }
+            if options.lenient_page_breaks {
+                sub_elements = split_lines_at_page_breaks(sub_elements);
+            }
+
+            for element in &sub_elements {
+                if let Some(closer) = block_closer_of(element) {
+                    if !closers.contains(&closer) {
+                        closers.push(closer);
+                    }
+                } else if let Some(i) = closers.iter().rposition(|c| c == element) {
+                    closers.remove(i);
+                }
+            }
 
             elements.extend(sub_elements);
         }
@@ -302,9 +631,10 @@ pub fn parse_ruby_txt(tokens: &[RubyTxtToken]) -> Result<ParsedRubyTxt> {
         tokens = &tokens[1..];
     }
 
+    let footer_line = line_at(tokens);
     let footer = {
         let footer_tokens = tokens.iter().map(|t| t).collect::<Vec<_>>();
-        let mut elements = parse_block(&footer_tokens)?;
+        let mut elements = parse_block(&footer_tokens, &ctx)?;
 
         // 最後の空行を消す
         while let Some(last) = elements.last() {
@@ -315,12 +645,259 @@ pub fn parse_ruby_txt(tokens: &[RubyTxtToken]) -> Result<ParsedRubyTxt> {
         }
         ensure!(!elements.is_empty(), "Footer is empty");
 
+        if options.track_lines {
+            elements = with_source_lines(elements, footer_line);
+        }
+
         elements
     };
 
-    Ok(ParsedRubyTxt {
+    let parsed = ParsedRubyTxt {
         header,
         body,
         footer,
-    })
+    };
+
+    if options.strict {
+        check_ruby_readings(&parsed)?;
+    }
+
+    Ok(parsed)
+}
+
+// 冒頭 (最初の空行まで) のみを構文解析する
+// 目録を作るときなど、題名・著者名のみが必要なときに本文・末尾を読まずに済ませる
+pub fn parse_header_only(tokens: &[RubyTxtToken]) -> Result<Vec<ParsedRubyTxtElement>> {
+    ensure!(!tokens.is_empty(), "Cannot parse empty array");
+
+    let options = ParseOptions::default();
+    let (_, header) = parse_header(tokens, &BlockContext::new(&options))?;
+    Ok(header)
+}
+
+// 冒頭を読み、残りの tokens とともに返す
+fn parse_header<'t>(
+    tokens: &'t [RubyTxtToken],
+    ctx: &BlockContext,
+) -> Result<(&'t [RubyTxtToken], Vec<ParsedRubyTxtElement>)> {
+    let mut tokens = tokens;
+
+    ensure!(
+        !matches!(tokens[0], RubyTxtToken::NewLine),
+        "Header starts with empty line"
+    );
+
+    let mut header_tokens = Vec::new();
+
+    loop {
+        let token = tokens.get(0).context("Failed to load header")?;
+        tokens = &tokens[1..];
+
+        if token == &RubyTxtToken::NewLine && tokens.get(0) == Some(&RubyTxtToken::NewLine) {
+            break;
+        }
+
+        header_tokens.push(token);
+    }
+
+    let mut elements = parse_block(&header_tokens, ctx)?;
+
+    // 最後の空行を消す
+    while let Some(last) = elements.last() {
+        if !matches!(last, ParsedRubyTxtElement::NewLine) {
+            break;
+        }
+        elements.pop();
+    }
+    ensure!(!elements.is_empty(), "Header is empty");
+
+    Ok((tokens, elements))
+}
+
+// 各行の先頭に行番号を置く
+// 改行の要素は元の .txt の改行と 1 対 1 に対応する
+fn with_source_lines(
+    elements: Vec<ParsedRubyTxtElement>,
+    first_line: usize,
+) -> Vec<ParsedRubyTxtElement> {
+    let mut ret = Vec::with_capacity(elements.len() + 1);
+    let mut line = first_line;
+    ret.push(ParsedRubyTxtElement::SourceLine { line });
+    for el in elements {
+        let is_new_line = matches!(el, ParsedRubyTxtElement::NewLine);
+        ret.push(el);
+        if is_new_line {
+            line += 1;
+            ret.push(ParsedRubyTxtElement::SourceLine { line });
+        }
+    }
+    ret
+}
+
+// ブロックの範囲の注記 (［＃ここから…］) に対応する終わりの注記
+fn block_closer_of(element: &ParsedRubyTxtElement) -> Option<ParsedRubyTxtElement> {
+    match element {
+        ParsedRubyTxtElement::JisageStartAnnotation { .. }
+        | ParsedRubyTxtElement::JisageWithOrikaeshiStartAnnotation { .. }
+        | ParsedRubyTxtElement::JisageAfterTentsukiStartAnnotation { .. } => {
+            Some(ParsedRubyTxtElement::JisageEndAnnotation)
+        }
+        ParsedRubyTxtElement::LineWidthStart { .. } => Some(ParsedRubyTxtElement::LineWidthEnd),
+        ParsedRubyTxtElement::JitsukiStartAnnotation => {
+            Some(ParsedRubyTxtElement::JitsukiEndAnnotation)
+        }
+        ParsedRubyTxtElement::JiyoseStartAnnotation { .. } => {
+            Some(ParsedRubyTxtElement::JiyoseEndAnnotation)
+        }
+        ParsedRubyTxtElement::KeigakomiStart => Some(ParsedRubyTxtElement::KeigakomiEnd),
+        _ => None,
+    }
+}
+
+// 空の 《》 は文字列にしているが、外字や注記を処理した結果として読みが空になることがある
+fn check_ruby_readings(parsed: &ParsedRubyTxt) -> Result<()> {
+    for (part, elements) in [
+        ("header", &parsed.header),
+        ("body", &parsed.body),
+        ("footer", &parsed.footer),
+    ] {
+        let mut line = 1;
+        for element in elements {
+            match element {
+                ParsedRubyTxtElement::NewLine => line += 1,
+                ParsedRubyTxtElement::Ruby { value } => ensure!(
+                    value.iter().any(|e| !e.text().is_empty()),
+                    "Empty ruby reading at line {} of {}: {:?}",
+                    line,
+                    part,
+                    value
+                ),
+                _ => {}
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// 全角英数字・記号を半角にする
+// 注記の書式に使われる文字と、注記の中 (字下げの字数など) はそのままにする
+// ただし注記の対象 (「」の中) は本文と一致させるために半角にする
+fn normalize_fullwidth(tokens: &[RubyTxtToken]) -> Vec<RubyTxtToken> {
+    let normalize_char = |c: char| match c {
+        '＃' | '［' | '］' | '｜' => c,
+        '\u{ff01}'..='\u{ff5e}' => char::from_u32(c as u32 - 0xfee0).unwrap(),
+        _ => c,
+    };
+
+    // 囲んでいる注記 (外字の注記なら true)
+    let mut annotations: Vec<bool> = Vec::new();
+    // 注記の中の「」の深さ
+    let mut quote_depth = 0usize;
+
+    tokens
+        .iter()
+        .map(|token| match token {
+            RubyTxtToken::AnnotationStart => {
+                annotations.push(false);
+                token.clone()
+            }
+            RubyTxtToken::GaijiAnnotationStart => {
+                annotations.push(true);
+                token.clone()
+            }
+            RubyTxtToken::AnnotationEnd => {
+                annotations.pop();
+                if annotations.is_empty() {
+                    quote_depth = 0;
+                }
+                token.clone()
+            }
+
+            RubyTxtToken::String(string) => match annotations.last() {
+                None => RubyTxtToken::String(string.chars().map(normalize_char).collect()),
+                Some(false) => {
+                    let mut normalized = String::with_capacity(string.len());
+                    for c in string.chars() {
+                        match c {
+                            '「' => quote_depth += 1,
+                            '」' => quote_depth = quote_depth.saturating_sub(1),
+                            _ => {}
+                        }
+                        normalized.push(if 0 < quote_depth {
+                            normalize_char(c)
+                        } else {
+                            c
+                        });
+                    }
+                    RubyTxtToken::String(normalized)
+                }
+                Some(true) => token.clone(),
+            },
+
+            _ => token.clone(),
+        })
+        .collect()
+}
+
+// "底本：" ("底本・初出：" もある)
+static REGEX_FOOTER_CHECKER: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^底本(・初出)?[：:]").unwrap());
+
+// 末尾の始まり ("底本：" で始まる行) の位置
+// 本文の会話などで行頭に "底本：" が現れることもあるので、空行か区切りの直後にあるものを優先し、
+// そのようなものがなければ最後のものとする
+fn find_footer_start(tokens: &[RubyTxtToken], options: &ParseOptions) -> Option<usize> {
+    let is_blank_or_separator =
+        |i: usize| tokens[i] == RubyTxtToken::NewLine || options.is_separator(tokens, i);
+
+    let candidates: Vec<_> = (0..tokens.len())
+        .filter(|&i| {
+            let RubyTxtToken::String(string) = &tokens[i] else {
+                return false;
+            };
+            REGEX_FOOTER_CHECKER.is_match(string)
+                && (i == 0 || tokens[i - 1] == RubyTxtToken::NewLine)
+        })
+        .collect();
+
+    candidates
+        .iter()
+        .copied()
+        .find(|&i| 2 <= i && is_blank_or_separator(i - 2))
+        .or(candidates.last().copied())
+}
+
+// 改丁・改ページ・改段の注記が独立した行になるように前後に改行を入れる
+fn split_lines_at_page_breaks(elements: Vec<ParsedRubyTxtElement>) -> Vec<ParsedRubyTxtElement> {
+    let is_page_break = |el: &ParsedRubyTxtElement| {
+        matches!(
+            el,
+            ParsedRubyTxtElement::KaichoAttention
+                | ParsedRubyTxtElement::KaipageAttention
+                | ParsedRubyTxtElement::KaidanAttention
+        )
+    };
+
+    let mut ret: Vec<ParsedRubyTxtElement> = Vec::with_capacity(elements.len());
+    let mut after_page_break = false;
+    for el in elements {
+        let is_new_line = matches!(el, ParsedRubyTxtElement::NewLine);
+
+        if after_page_break && !is_new_line {
+            ret.push(ParsedRubyTxtElement::NewLine);
+        }
+
+        if is_page_break(&el) {
+            if let Some(last) = ret.last() {
+                if !matches!(last, ParsedRubyTxtElement::NewLine) {
+                    ret.push(ParsedRubyTxtElement::NewLine);
+                }
+            }
+        }
+
+        after_page_break = is_page_break(&el);
+        ret.push(el);
+    }
+    ret
 }
@@ -0,0 +1,161 @@
+use crate::ruby_txt::renderer::{
+    ruby_base_start, RenderedRubyTxt, RenderedRubyTxtComponent, RenderedRubyTxtLine,
+};
+
+// ルビの書き出し方
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RubyMode {
+    // ルビを書かない
+    Omit,
+    // 青空文庫の書式で "○○《●●》" と書く
+    // ｜ なしでは範囲が変わるときは "｜○○《●●》" と書く
+    // 文字としての 《 》 ｜ ［＃ は外字の注記にする
+    SourceNotation,
+    // "○○（●●）" と書く
+    Parenthesized,
+}
+
+// プレーンテキストに書き出す
+// 冒頭・本文・末尾は空行で区切る
+pub fn export_plain_text(rendered: &RenderedRubyTxt, ruby_mode: RubyMode) -> String {
+    [&rendered.header, &rendered.body, &rendered.footer]
+        .iter()
+        .map(|lines| {
+            lines
+                .iter()
+                .map(|line| line_to_plain_text(line, ruby_mode))
+                .collect::<Vec<_>>()
+                .join("\n")
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+impl RenderedRubyTxtLine {
+    // 注記を除いた 1 行の文字列 (デバッグや検索用)
+    // 字下げは全角空白で表し、ルビは "○○（●●）" と書く
+    pub fn to_plain_string(&self) -> String {
+        self.to_plain_string_with(RubyMode::Parenthesized)
+    }
+
+    // to_plain_string のルビを書かないもの
+    pub fn to_plain_string_no_ruby(&self) -> String {
+        self.to_plain_string_with(RubyMode::Omit)
+    }
+
+    fn to_plain_string_with(&self, ruby_mode: RubyMode) -> String {
+        "　".repeat(self.jisage.level0) + &line_to_plain_text(self, ruby_mode)
+    }
+}
+
+fn line_to_plain_text(line: &RenderedRubyTxtLine, ruby_mode: RubyMode) -> String {
+    let mut ret = String::new();
+
+    write_components(&mut ret, &line.components, ruby_mode);
+
+    // 字寄せは行末に続ける
+    if let Some(jiyose) = &line.jiyose {
+        for jiyose_line in &jiyose.lines {
+            write_components(&mut ret, jiyose_line, ruby_mode);
+        }
+    }
+
+    ret
+}
+
+fn write_components(
+    out: &mut String,
+    components: &[RenderedRubyTxtComponent],
+    ruby_mode: RubyMode,
+) {
+    for component in components {
+        match component {
+            RenderedRubyTxtComponent::String { value } => match ruby_mode {
+                RubyMode::SourceNotation => out.push_str(&escape_source_notation(value)),
+                _ => out.push_str(value),
+            },
+
+            RenderedRubyTxtComponent::UnknownAnnotation { args: _ } => {}
+
+            // 青空文庫の書式では元に戻す
+            RenderedRubyTxtComponent::EmptyAnnotation => {
+                if ruby_mode == RubyMode::SourceNotation {
+                    out.push_str("［＃］");
+                }
+            }
+
+            RenderedRubyTxtComponent::Ruby { ruby, children } => match ruby_mode {
+                RubyMode::Omit => write_components(out, children, ruby_mode),
+
+                RubyMode::SourceNotation => {
+                    let mut base = String::new();
+                    write_components(&mut base, children, ruby_mode);
+
+                    let mut reading = String::new();
+                    write_components(&mut reading, ruby, ruby_mode);
+
+                    // 外字の注記を含むときは範囲を判断できないので常に ｜ を付ける
+                    if base.contains("※［＃") || needs_position_marker(out, &base) {
+                        out.push('｜');
+                    }
+                    out.push_str(&base);
+                    out.push('《');
+                    out.push_str(&reading);
+                    out.push('》');
+                }
+
+                RubyMode::Parenthesized => {
+                    write_components(out, children, ruby_mode);
+                    out.push('（');
+                    write_components(out, ruby, RubyMode::Omit);
+                    out.push('）');
+                }
+            },
+
+            RenderedRubyTxtComponent::Midashi {
+                level: _,
+                style: _,
+                children,
+            } => write_components(out, children, ruby_mode),
+
+            RenderedRubyTxtComponent::Kogaki {
+                position: _,
+                children,
+            } => write_components(out, children, ruby_mode),
+
+            RenderedRubyTxtComponent::Caption { children } => {
+                write_components(out, children, ruby_mode)
+            }
+
+            RenderedRubyTxtComponent::Figure { image: _, caption } => {
+                write_components(out, caption, ruby_mode)
+            }
+
+            RenderedRubyTxtComponent::LayoutHint { kind: _, value: _ } => {}
+
+            RenderedRubyTxtComponent::Tmp { data: _ } => {}
+        }
+    }
+}
+
+// 青空文庫の書式で特別な意味を持つ文字を外字の注記にする
+fn escape_source_notation(value: &str) -> String {
+    let mut ret = String::new();
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '《' => ret.push_str("※［＃始め二重山括弧、U+300A］"),
+            '》' => ret.push_str("※［＃終わり二重山括弧、U+300B］"),
+            '｜' => ret.push_str("※［＃縦線、U+FF5C］"),
+            '［' if chars.peek() == Some(&'＃') => ret.push_str("※［＃始め角括弧、U+FF3B］"),
+            c => ret.push(c),
+        }
+    }
+    ret
+}
+
+// ｜ なしで書いたときに、再びパースしてもルビの範囲が base と一致するか
+fn needs_position_marker(preceding: &str, base: &str) -> bool {
+    let text = format!("{}{}", preceding, base);
+    text[ruby_base_start(&text, false)..] != *base
+}
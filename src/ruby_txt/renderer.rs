@@ -1,16 +1,17 @@
 use anyhow::{bail, ensure, Context, Result};
 use serde::{Deserialize, Serialize};
+use unicode_segmentation::UnicodeSegmentation;
 
 use crate::{
     ruby_txt::{
         parser::{ParsedRubyTxt, ParsedRubyTxtElement},
         tokenizer::RubyTxtToken,
-        utility::{MidashiLevel, MidashiStyle},
+        utility::{KogakiPosition, MidashiLevel, MidashiStyle},
     },
     utility::str::CharType,
 };
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct RenderedRubyTxt {
     pub header: Vec<RenderedRubyTxtLine>,
@@ -18,11 +19,75 @@ pub struct RenderedRubyTxt {
     pub footer: Vec<RenderedRubyTxtLine>,
 }
 
+#[derive(Default)]
+pub struct RenderOptions {
+    // 傍点・傍線・太字・斜体を捨て、対象の文字列のみを残す
+    pub drop_decorations: bool,
+
+    // 旧仮名遣いの作品か (作品一覧の文字遣い種別)
+    // ルビの掛かる範囲の判定が少し変わる (ruby_base_start)
+    pub old_kana: bool,
+}
+
+impl RenderedRubyTxt {
+    // 本文の行を段落にまとめる
+    // 空行・改ページなど・字下げの変わるところで区切り、それ以外の続く行をつなげる
+    // 字寄せのある行は 1 行で段落とし、主要素の後に字寄せを続ける
+    pub fn paragraphs(&self) -> Vec<Vec<RenderedRubyTxtComponent>> {
+        let mut paragraphs = Vec::new();
+
+        let mut current: Vec<RenderedRubyTxtComponent> = Vec::new();
+        let mut current_jisage: Option<&Jisage> = None;
+
+        for line in &self.body {
+            let breaks = line.is_blank(true)
+                || line.page_style != PageStyle::Continuous
+                || line.jiyose.is_some()
+                || current_jisage.is_some_and(|jisage| jisage != &line.jisage);
+            if breaks && !current.is_empty() {
+                paragraphs.push(std::mem::take(&mut current));
+            }
+            current_jisage = Some(&line.jisage);
+
+            if line.is_blank(true) {
+                continue;
+            }
+
+            current.extend(line.components.iter().cloned());
+
+            if let Some(jiyose) = &line.jiyose {
+                for jiyose_line in &jiyose.lines {
+                    current.extend(jiyose_line.iter().cloned());
+                }
+                paragraphs.push(std::mem::take(&mut current));
+                current_jisage = None;
+            }
+        }
+
+        if !current.is_empty() {
+            paragraphs.push(current);
+        }
+
+        paragraphs
+    }
+}
+
 // 注記などを基に、描画するに適切な構造を求める
 pub fn render_ruby_txt(parsed: &ParsedRubyTxt) -> Result<RenderedRubyTxt> {
-    let header = render_block(&parsed.header.iter().map(|e| e).collect::<Vec<_>>())?;
-    let body = render_block(&parsed.body.iter().map(|e| e).collect::<Vec<_>>())?;
-    let footer = render_block(&parsed.footer.iter().map(|e| e).collect::<Vec<_>>())?;
+    render_ruby_txt_with_options(parsed, &RenderOptions::default())
+}
+
+pub fn render_ruby_txt_with_options(
+    parsed: &ParsedRubyTxt,
+    options: &RenderOptions,
+) -> Result<RenderedRubyTxt> {
+    let mut header = render_block(&parsed.header.iter().collect::<Vec<_>>(), options)?;
+    let mut body = render_block(&parsed.body.iter().collect::<Vec<_>>(), options)?;
+    let mut footer = render_block(&parsed.footer.iter().collect::<Vec<_>>(), options)?;
+
+    for line in header.iter_mut().chain(&mut body).chain(&mut footer) {
+        line.merge_adjacent_strings();
+    }
     Ok(RenderedRubyTxt {
         header,
         body,
@@ -30,6 +95,27 @@ pub fn render_ruby_txt(parsed: &ParsedRubyTxt) -> Result<RenderedRubyTxt> {
     })
 }
 
+// 冒頭・本文・末尾を 1 つの行の並びとして描画する
+// 各部の境目は改ページとする
+pub fn render_ruby_txt_combined(parsed: &ParsedRubyTxt) -> Result<Vec<RenderedRubyTxtLine>> {
+    let rendered = render_ruby_txt(parsed)?;
+
+    let mut lines = Vec::new();
+    for part in [rendered.header, rendered.body, rendered.footer] {
+        let mut part = part.into_iter();
+        if let Some(mut first) = part.next() {
+            // 既にページの指定があればそれに従う
+            if !lines.is_empty() && first.page_style == PageStyle::Continuous {
+                first.page_style = PageStyle::Kaipage { center: false };
+            }
+            lines.push(first);
+        }
+        lines.extend(part);
+    }
+
+    Ok(lines)
+}
+
 // ページに対する状態
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
@@ -45,29 +131,69 @@ pub enum PageStyle {
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct Jisage {
-    level0: usize, // 1 行目
-    level1: usize, // 2 行目以降
+    pub(super) level0: usize, // 1 行目
+    level1: usize,            // 2 行目以降
+}
+
+impl Jisage {
+    pub fn new(level0: usize, level1: usize) -> Self {
+        Self { level0, level1 }
+    }
+
+    pub fn level0(&self) -> usize {
+        self.level0
+    }
+
+    pub fn level1(&self) -> usize {
+        self.level1
+    }
 }
 
 // 地寄せ
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct Jiyose {
     level: usize, // 0 なら地付き
-    lines: Vec<Vec<RenderedRubyTxtComponent>>,
+    pub(super) lines: Vec<Vec<RenderedRubyTxtComponent>>,
+}
+
+impl Jiyose {
+    pub fn new(level: usize, lines: Vec<Vec<RenderedRubyTxtComponent>>) -> Self {
+        Self { level, lines }
+    }
+
+    pub fn level(&self) -> usize {
+        self.level
+    }
+
+    pub fn lines(&self) -> &[Vec<RenderedRubyTxtComponent>] {
+        &self.lines
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct RenderedRubyTxtLine {
     page_style: PageStyle,
-    jisage: Jisage,
+    pub(super) jisage: Jisage,
 
     // 主要素
-    components: Vec<RenderedRubyTxtComponent>,
+    pub(super) components: Vec<RenderedRubyTxtComponent>,
 
     // 字寄せ
-    jiyose: Option<Jiyose>,
+    pub(super) jiyose: Option<Jiyose>,
+
+    // 1 行の字数 (［＃ここから○字詰め］)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    line_width: Option<usize>,
+
+    // 罫囲みの中の行か (［＃ここから罫囲み］)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    keigakomi: bool,
+
+    // 元の .txt の行番号 (ParseOptions::track_lines のときのみ)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source_line: Option<usize>,
 }
 
 impl RenderedRubyTxtLine {
@@ -82,9 +208,44 @@ impl RenderedRubyTxtLine {
             components: Vec::new(),
 
             jiyose: None,
+
+            line_width: None,
+
+            keigakomi: false,
+
+            source_line: None,
         }
     }
 
+    // 値の変更は set_* のみで行うので、外からは読み出しのみとする
+    pub fn page_style(&self) -> &PageStyle {
+        &self.page_style
+    }
+
+    pub fn jisage(&self) -> &Jisage {
+        &self.jisage
+    }
+
+    pub fn components(&self) -> &[RenderedRubyTxtComponent] {
+        &self.components
+    }
+
+    pub fn jiyose(&self) -> Option<&Jiyose> {
+        self.jiyose.as_ref()
+    }
+
+    pub fn line_width(&self) -> Option<usize> {
+        self.line_width
+    }
+
+    pub fn keigakomi(&self) -> bool {
+        self.keigakomi
+    }
+
+    pub fn source_line(&self) -> Option<usize> {
+        self.source_line
+    }
+
     fn extract_components(self) -> Result<Vec<RenderedRubyTxtComponent>> {
         ensure!(
             self.page_style == PageStyle::Continuous,
@@ -146,11 +307,11 @@ impl RenderedRubyTxtLine {
     }
 
     // 空行かどうか
-    // ただし空白は許す
+    // ただし空白は許す (半角の空白で字下げしているものもある)
     fn is_blank(&self, check_jiyose: bool) -> bool {
         for c in &self.components {
             for c in c.text().chars() {
-                if c != '　' {
+                if c != '　' && c != ' ' {
                     return false;
                 }
             }
@@ -181,10 +342,30 @@ impl RenderedRubyTxtLine {
         }
     }
 
+    // 隣り合う文字列をまとめる
+    // ルビなどの範囲を取り出した後に残ることがある
+    pub fn merge_adjacent_strings(&mut self) {
+        merge_adjacent_strings(&mut self.components);
+        if let Some(jiyose) = &mut self.jiyose {
+            for line in &mut jiyose.lines {
+                merge_adjacent_strings(line);
+            }
+        }
+    }
+
     fn pop(&mut self) -> Option<RenderedRubyTxtComponent> {
         self.components.pop()
     }
 
+    // 最後の要素が文字列ならば、その最後の 1 文字 (書記素) を抜き出す
+    fn pop_last_grapheme(&mut self) -> Option<Vec<RenderedRubyTxtComponent>> {
+        let Some(RenderedRubyTxtComponent::String { value }) = self.components.last() else {
+            return None;
+        };
+        let last = value.graphemes(true).next_back()?.to_owned();
+        self.pop_last_string(&last).ok()
+    }
+
     // この行の text が string で終わるならば、その要素を抜き出す
     fn pop_last_string(&mut self, string: &str) -> Result<Vec<RenderedRubyTxtComponent>> {
         let mut ret = Vec::new();
@@ -222,6 +403,19 @@ impl RenderedRubyTxtLine {
                         });
                     }
 
+                    // 読みは親文字の一部には付け替えられないので、ルビは分けずに元に戻して失敗する
+                    RenderedRubyTxtComponent::Ruby { .. } => {
+                        let message = format!(
+                            r#"Cannot pop "{}": "{}" is a part of ruby base: {:?}"#,
+                            &string, &left, &last
+                        );
+                        self.push(last);
+                        for c in ret.into_iter().rev() {
+                            self.push(c);
+                        }
+                        bail!(message);
+                    }
+
                     _ => {
                         bail!("Cannot split to pop: {:?}", last);
                     }
@@ -246,7 +440,7 @@ impl RenderedRubyTxtLine {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case", tag = "type")]
 pub enum RenderedRubyTxtComponent {
     String {
@@ -255,6 +449,8 @@ pub enum RenderedRubyTxtComponent {
     UnknownAnnotation {
         args: Vec<RenderedRubyTxtComponent>,
     },
+    // ［＃］ (入力者注の印)
+    EmptyAnnotation,
 
     Ruby {
         ruby: Vec<RenderedRubyTxtComponent>,
@@ -267,16 +463,44 @@ pub enum RenderedRubyTxtComponent {
         children: Vec<RenderedRubyTxtComponent>,
     },
 
+    Kogaki {
+        position: KogakiPosition,
+        children: Vec<RenderedRubyTxtComponent>,
+    },
+
+    // ［＃キャプション］…［＃キャプション終わり］, ［＃「○○」はキャプション］
+    Caption {
+        children: Vec<RenderedRubyTxtComponent>,
+    },
+    // キャプションの付いた図
+    Figure {
+        image: FigureImage,
+        caption: Vec<RenderedRubyTxtComponent>,
+    },
+
+    // 描画には直接関わらない組版の指定
+    LayoutHint {
+        kind: String,
+        value: String,
+    },
+
     Tmp {
         data: ParsedRubyTxtElement,
     },
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FigureImage {
+    pub path: String,
+    pub alt: String,
+}
+
 impl RenderedRubyTxtComponent {
-    fn text(&self) -> String {
+    pub(super) fn text(&self) -> String {
         match &self {
             &Self::String { value } => value.clone(),
             &Self::UnknownAnnotation { args: _ } => "".to_owned(),
+            &Self::EmptyAnnotation => "".to_owned(),
             &Self::Ruby { ruby: _, children } => {
                 children.iter().map(|c| c.text()).collect::<String>()
             }
@@ -285,53 +509,156 @@ impl RenderedRubyTxtComponent {
                 style: _,
                 children,
             } => children.iter().map(|c| c.text()).collect::<String>(),
+            &Self::Kogaki {
+                position: _,
+                children,
+            } => children.iter().map(|c| c.text()).collect::<String>(),
+            &Self::Caption { children } => children.iter().map(|c| c.text()).collect::<String>(),
+            &Self::Figure { image: _, caption } => {
+                caption.iter().map(|c| c.text()).collect::<String>()
+            }
+            &Self::LayoutHint { kind: _, value: _ } => "".to_owned(),
             &Self::Tmp { data: _ } => "".to_owned(),
         }
     }
+
+    // 自身と子孫を深さ優先 (行きがけ順) で並べる
+    // ルビは親文字、読みの順に辿る
+    pub fn flatten(&self) -> Vec<&RenderedRubyTxtComponent> {
+        let mut ret = Vec::new();
+        self.flatten_into(&mut ret);
+        ret
+    }
+
+    fn flatten_into<'a>(&'a self, out: &mut Vec<&'a RenderedRubyTxtComponent>) {
+        out.push(self);
+
+        let children: &[&[RenderedRubyTxtComponent]] = match self {
+            Self::String { .. }
+            | Self::EmptyAnnotation
+            | Self::LayoutHint { .. }
+            | Self::Tmp { .. } => &[],
+            Self::UnknownAnnotation { args } => &[args],
+            Self::Ruby { ruby, children } => &[children, ruby],
+            Self::Midashi { children, .. }
+            | Self::Kogaki { children, .. }
+            | Self::Caption { children } => &[children],
+            Self::Figure { caption, .. } => &[caption],
+        };
+        for c in children.iter().flat_map(|c| c.iter()) {
+            c.flatten_into(out);
+        }
+    }
+
+    // flatten したもののうち文字列のみ
+    // text と異なり、ルビの読みや注記の引数も含む
+    pub fn all_strings(&self) -> Vec<&str> {
+        self.flatten()
+            .into_iter()
+            .filter_map(|c| match c {
+                Self::String { value } => Some(value.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+fn merge_adjacent_strings(components: &mut Vec<RenderedRubyTxtComponent>) {
+    let mut merged: Vec<RenderedRubyTxtComponent> = Vec::with_capacity(components.len());
+    for mut component in components.drain(..) {
+        match &mut component {
+            RenderedRubyTxtComponent::String { value } => {
+                if let Some(RenderedRubyTxtComponent::String { value: last }) = merged.last_mut() {
+                    last.push_str(value);
+                    continue;
+                }
+            }
+            RenderedRubyTxtComponent::UnknownAnnotation { args } => merge_adjacent_strings(args),
+            RenderedRubyTxtComponent::Ruby { ruby, children } => {
+                merge_adjacent_strings(ruby);
+                merge_adjacent_strings(children);
+            }
+            RenderedRubyTxtComponent::Midashi { children, .. }
+            | RenderedRubyTxtComponent::Kogaki { children, .. }
+            | RenderedRubyTxtComponent::Caption { children } => merge_adjacent_strings(children),
+            RenderedRubyTxtComponent::Figure { caption, .. } => merge_adjacent_strings(caption),
+            RenderedRubyTxtComponent::EmptyAnnotation
+            | RenderedRubyTxtComponent::LayoutHint { .. }
+            | RenderedRubyTxtComponent::Tmp { .. } => {}
+        }
+        merged.push(component);
+    }
+    *components = merged;
 }
 
 // 注記などを基に、描画するに適切な構造を求める
-pub fn render_block(elements: &[&ParsedRubyTxtElement]) -> Result<Vec<RenderedRubyTxtLine>> {
+pub fn render_block(
+    elements: &[&ParsedRubyTxtElement],
+    options: &RenderOptions,
+) -> Result<Vec<RenderedRubyTxtLine>> {
     let mut elements = elements;
 
     let mut lines = vec![RenderedRubyTxtLine::new()];
 
     // ブロックで宣言されたレイアウト
     let mut global_jisage: Option<Jisage> = None;
+    let mut global_line_width: Option<usize> = None;
+    let mut global_keigakomi = false;
 
     while !elements.is_empty() {
         match &elements[0] {
             ParsedRubyTxtElement::String { value } => {
-                lines.last_mut().unwrap().push_str(&value);
+                last_line(&mut lines).push_str(&value);
                 elements = &elements[1..];
             }
 
             ParsedRubyTxtElement::NewLine => {
                 let mut line = RenderedRubyTxtLine::new();
 
+                // 改ページなどで分けた行は元の行と同じ行番号にする
+                // 元の .txt の行の始まりであれば直後の SourceLine で上書きされる
+                line.source_line = lines.last().and_then(|l| l.source_line);
+
                 if let Some(global_jisage) = &global_jisage {
                     line.set_jisage(global_jisage.clone()).unwrap();
                 }
+                line.line_width = global_line_width;
+                line.keigakomi = global_keigakomi;
                 lines.push(line);
 
                 elements = &elements[1..];
             }
 
-            ParsedRubyTxtElement::UnknownAnnotation { args } => {
-                let args = render_line_components(&args.iter().map(|a| a).collect::<Vec<_>>())
-                    .with_context(|| format!("Failed to render unknown annotation: {:?}", args))?;
+            ParsedRubyTxtElement::UnknownAnnotation { raw: _, args } => {
+                let args =
+                    render_line_components(&args.iter().map(|a| a).collect::<Vec<_>>(), options)
+                        .with_context(|| {
+                            format!("Failed to render unknown annotation: {:?}", args)
+                        })?;
 
-                lines
-                    .last_mut()
-                    .unwrap()
-                    .push(RenderedRubyTxtComponent::UnknownAnnotation { args });
+                last_line(&mut lines).push(RenderedRubyTxtComponent::UnknownAnnotation { args });
+                elements = &elements[1..];
+            }
+
+            ParsedRubyTxtElement::EmptyAnnotation => {
+                last_line(&mut lines).push(RenderedRubyTxtComponent::EmptyAnnotation);
+                elements = &elements[1..];
+            }
+
+            // 原文は描画しない
+            ParsedRubyTxtElement::SourceLine { line } => {
+                last_line(&mut lines).source_line = Some(*line);
+                elements = &elements[1..];
+            }
+
+            ParsedRubyTxtElement::AnnotationSource { raw: _ } => {
                 elements = &elements[1..];
             }
 
             ParsedRubyTxtElement::PositionMarker => {
                 elements = &elements[1..];
 
-                let line = lines.last_mut().unwrap();
+                let line = last_line(&mut lines);
                 let mut target = Vec::new();
 
                 let mut elements_for_marker = elements;
@@ -346,11 +673,19 @@ pub fn render_block(elements: &[&ParsedRubyTxtElement]) -> Result<Vec<RenderedRu
                         ParsedRubyTxtElement::Ruby { value } => {
                             let ruby = render_line_components(
                                 &value.iter().map(|v| v).collect::<Vec<_>>(),
+                                options,
                             )
                             .with_context(|| format!("Failed to render ruby: {:?}", value))?;
-                            let children = render_line_components(&target).with_context(|| {
-                                format!("Failed to render ruby children: {:?}", value)
-                            })?;
+                            let children = if target.is_empty() {
+                                // "向｜《むか》" のように ｜ の位置を誤っているものは直前の 1 文字に掛ける
+                                line.pop_last_grapheme().with_context(|| {
+                                    format!("Cannot find elements to set ruby {:?}", value)
+                                })?
+                            } else {
+                                render_line_components(&target, options).with_context(|| {
+                                    format!("Failed to render ruby children: {:?}", value)
+                                })?
+                            };
                             line.push(RenderedRubyTxtComponent::Ruby { ruby, children });
                             elements_for_marker = &elements_for_marker[1..];
                             break true;
@@ -372,35 +707,27 @@ pub fn render_block(elements: &[&ParsedRubyTxtElement]) -> Result<Vec<RenderedRu
             }
 
             ParsedRubyTxtElement::Ruby { value } => {
-                let ruby = render_line_components(&value.iter().map(|v| v).collect::<Vec<_>>())
-                    .with_context(|| format!("Failed to render ruby: {:?}", value))?;
+                let ruby =
+                    render_line_components(&value.iter().map(|v| v).collect::<Vec<_>>(), options)
+                        .with_context(|| format!("Failed to render ruby: {:?}", value))?;
 
-                let line = lines.last_mut().unwrap();
+                let line = last_line(&mut lines);
                 let last = line
                     .pop()
                     .with_context(|| format!("Cannod find elements to set ruby {:?}", ruby))?;
                 match last {
                     RenderedRubyTxtComponent::String { value } => {
-                        let value_chars: Vec<_> = value.chars().collect();
-
-                        let mut ruby_start_index = value_chars.len();
-                        let last_char_type = CharType::from(*value_chars.last().unwrap());
-                        for c in value_chars.iter().rev() {
-                            if CharType::from(*c) != last_char_type {
-                                break;
-                            }
-                            ruby_start_index -= 1;
-                        }
+                        let ruby_start_index = ruby_base_start(&value, options.old_kana);
 
                         if 0 < ruby_start_index {
                             line.push(RenderedRubyTxtComponent::String {
-                                value: value_chars[..ruby_start_index].iter().collect(),
+                                value: value[..ruby_start_index].to_owned(),
                             });
                         }
                         line.push(RenderedRubyTxtComponent::Ruby {
                             ruby,
                             children: vec![RenderedRubyTxtComponent::String {
-                                value: value_chars[ruby_start_index..].iter().collect(),
+                                value: value[ruby_start_index..].to_owned(),
                             }],
                         });
                     }
@@ -426,16 +753,16 @@ pub fn render_block(elements: &[&ParsedRubyTxtElement]) -> Result<Vec<RenderedRu
                     continue;
                 }
 
-                ensure!(
-                    matches!(elements[0], ParsedRubyTxtElement::NewLine),
-                    "Invalid kaicho"
-                );
-                elements = &elements[1..];
+                // ［＃改丁、ページの左右中央］ では改行の前に左右中央が続く
+                if !matches!(elements[0], ParsedRubyTxtElement::PageCenterAnnotation) {
+                    ensure!(
+                        matches!(elements[0], ParsedRubyTxtElement::NewLine),
+                        "Invalid kaicho"
+                    );
+                    elements = &elements[1..];
+                }
 
-                lines
-                    .last_mut()
-                    .unwrap()
-                    .set_page_style(PageStyle::Kaicho { center: false })?;
+                last_line(&mut lines).set_page_style(PageStyle::Kaicho { center: false })?;
             }
 
             ParsedRubyTxtElement::KaipageAttention => {
@@ -444,16 +771,15 @@ pub fn render_block(elements: &[&ParsedRubyTxtElement]) -> Result<Vec<RenderedRu
                     continue;
                 }
 
-                ensure!(
-                    matches!(elements[0], ParsedRubyTxtElement::NewLine),
-                    "Invalid kaipage"
-                );
-                elements = &elements[1..];
+                if !matches!(elements[0], ParsedRubyTxtElement::PageCenterAnnotation) {
+                    ensure!(
+                        matches!(elements[0], ParsedRubyTxtElement::NewLine),
+                        "Invalid kaipage"
+                    );
+                    elements = &elements[1..];
+                }
 
-                lines
-                    .last_mut()
-                    .unwrap()
-                    .set_page_style(PageStyle::Kaipage { center: false })?;
+                last_line(&mut lines).set_page_style(PageStyle::Kaipage { center: false })?;
             }
 
             ParsedRubyTxtElement::KaimihirakiAttention => {
@@ -468,10 +794,7 @@ pub fn render_block(elements: &[&ParsedRubyTxtElement]) -> Result<Vec<RenderedRu
                 );
                 elements = &elements[1..];
 
-                lines
-                    .last_mut()
-                    .unwrap()
-                    .set_page_style(PageStyle::Kaimihiraki)?;
+                last_line(&mut lines).set_page_style(PageStyle::Kaimihiraki)?;
             }
 
             ParsedRubyTxtElement::KaidanAttention => {
@@ -480,30 +803,33 @@ pub fn render_block(elements: &[&ParsedRubyTxtElement]) -> Result<Vec<RenderedRu
                     continue;
                 }
 
-                ensure!(
-                    matches!(elements[0], ParsedRubyTxtElement::NewLine),
-                    "Invalid kaidan"
-                );
-                elements = &elements[1..];
+                if !matches!(elements[0], ParsedRubyTxtElement::PageCenterAnnotation) {
+                    ensure!(
+                        matches!(elements[0], ParsedRubyTxtElement::NewLine),
+                        "Invalid kaidan"
+                    );
+                    elements = &elements[1..];
+                }
 
-                lines
-                    .last_mut()
-                    .unwrap()
-                    .set_page_style(PageStyle::Kaidan { center: false })?;
+                last_line(&mut lines).set_page_style(PageStyle::Kaidan { center: false })?;
             }
 
             ParsedRubyTxtElement::JisageAnnotation { level } => {
                 elements = &elements[1..];
 
-                let line = lines.last_mut().unwrap();
+                let line = last_line(&mut lines);
                 ensure!(line.is_blank(false), "Invalid one-line jisage");
 
-                line.jisage.level0 += *level;
-                line.jisage.level1 += *level;
+                line.jisage.level0 = line.jisage.level0.saturating_add(*level);
+                line.jisage.level1 = line.jisage.level1.saturating_add(*level);
             }
 
+            // 終わりの注記がなければブロックの終わりまで字下げする
             ParsedRubyTxtElement::JisageStartAnnotation { level } => {
-                ensure!(lines.pop().unwrap().is_empty(), "Invalid jisage-start");
+                ensure!(
+                    pop_directive_line(&mut lines, &elements[1..]).is_empty(),
+                    "Invalid jisage-start"
+                );
                 elements = &elements[1..];
 
                 global_jisage = Some(Jisage {
@@ -514,7 +840,7 @@ pub fn render_block(elements: &[&ParsedRubyTxtElement]) -> Result<Vec<RenderedRu
 
             ParsedRubyTxtElement::JisageWithOrikaeshiStartAnnotation { level0, level1 } => {
                 ensure!(
-                    lines.pop().unwrap().is_empty(),
+                    pop_directive_line(&mut lines, &elements[1..]).is_empty(),
                     "Invalid jisage-with-orikaeshi-start"
                 );
                 elements = &elements[1..];
@@ -527,7 +853,7 @@ pub fn render_block(elements: &[&ParsedRubyTxtElement]) -> Result<Vec<RenderedRu
 
             ParsedRubyTxtElement::JisageAfterTentsukiStartAnnotation { level } => {
                 ensure!(
-                    lines.pop().unwrap().is_empty(),
+                    pop_directive_line(&mut lines, &elements[1..]).is_empty(),
                     "Invalid jisage-after-tentsuki-start"
                 );
                 elements = &elements[1..];
@@ -538,14 +864,67 @@ pub fn render_block(elements: &[&ParsedRubyTxtElement]) -> Result<Vec<RenderedRu
                 });
             }
 
+            ParsedRubyTxtElement::KaigiyoTentsukiAnnotation => {
+                elements = &elements[1..];
+
+                let line = last_line(&mut lines);
+                ensure!(line.is_blank(false), "Invalid kaigyo-tentsuki");
+
+                // 1 行目のみ天付きにし、折り返し以降はそのまま
+                line.jisage.level0 = 0;
+            }
+
             ParsedRubyTxtElement::JisageEndAnnotation => {
-                ensure!(lines.pop().unwrap().is_empty(), "Invalid jisage-end");
+                ensure!(
+                    pop_directive_line(&mut lines, &elements[1..]).is_empty(),
+                    "Invalid jisage-end"
+                );
 
                 // 規格外の注記で字下げが始まっている可能性があるのでエラーにしない
                 elements = &elements[1..];
                 global_jisage = None;
             }
 
+            ParsedRubyTxtElement::LineWidthStart { chars } => {
+                ensure!(
+                    pop_directive_line(&mut lines, &elements[1..]).is_empty(),
+                    "Invalid line-width-start"
+                );
+                elements = &elements[1..];
+
+                global_line_width = Some(*chars);
+            }
+
+            ParsedRubyTxtElement::LineWidthEnd => {
+                ensure!(
+                    pop_directive_line(&mut lines, &elements[1..]).is_empty(),
+                    "Invalid line-width-end"
+                );
+                elements = &elements[1..];
+
+                global_line_width = None;
+            }
+
+            ParsedRubyTxtElement::KeigakomiStart => {
+                ensure!(
+                    pop_directive_line(&mut lines, &elements[1..]).is_empty(),
+                    "Invalid keigakomi-start"
+                );
+                elements = &elements[1..];
+
+                global_keigakomi = true;
+            }
+
+            ParsedRubyTxtElement::KeigakomiEnd => {
+                ensure!(
+                    pop_directive_line(&mut lines, &elements[1..]).is_empty(),
+                    "Invalid keigakomi-end"
+                );
+                elements = &elements[1..];
+
+                global_keigakomi = false;
+            }
+
             ParsedRubyTxtElement::JitsukiAnnotation => {
                 elements = &elements[1..];
 
@@ -558,16 +937,16 @@ pub fn render_block(elements: &[&ParsedRubyTxtElement]) -> Result<Vec<RenderedRu
                     elements = &elements[1..];
                 }
 
-                let jitsuki_line = render_line_components(&jitsuki_elements)
+                let jitsuki_line = render_line_components(&jitsuki_elements, options)
                     .context("Failed to render a line with jitsuki")?;
-                lines.last_mut().unwrap().set_jiyose(Jiyose {
+                last_line(&mut lines).set_jiyose(Jiyose {
                     level: 0,
                     lines: vec![jitsuki_line],
                 })?;
             }
 
             ParsedRubyTxtElement::JitsukiStartAnnotation => {
-                ensure!(lines.pop().unwrap().is_empty(), "Invalid jitsuki-start");
+                ensure!(pop_line(&mut lines).is_empty(), "Invalid jitsuki-start");
                 ensure!(
                     matches!(elements.get(1), Some(ParsedRubyTxtElement::NewLine)),
                     "Invalid jitsuki-start"
@@ -575,15 +954,27 @@ pub fn render_block(elements: &[&ParsedRubyTxtElement]) -> Result<Vec<RenderedRu
                 elements = &elements[2..];
 
                 let mut jitsuki_elements = Vec::new();
+                let mut closed = false;
                 while !elements.is_empty() {
                     let el = elements[0];
                     elements = &elements[1..];
 
                     if matches!(el, ParsedRubyTxtElement::JitsukiEndAnnotation) {
+                        closed = true;
                         break;
                     }
                     jitsuki_elements.push(el);
                 }
+                ensure!(closed, "jitsuki block is not closed");
+
+                // 終わりの注記の原文・行番号は要らない
+                while let Some(
+                    ParsedRubyTxtElement::AnnotationSource { .. }
+                    | ParsedRubyTxtElement::SourceLine { .. },
+                ) = jitsuki_elements.last()
+                {
+                    jitsuki_elements.pop();
+                }
 
                 // "［＃ここで地付き終わり］" 前の改行を取り除く
                 ensure!(
@@ -595,11 +986,11 @@ pub fn render_block(elements: &[&ParsedRubyTxtElement]) -> Result<Vec<RenderedRu
                 );
 
                 // 地付きブロックは全行を既にある 1 行に入れる
-                let jitsuki_lines: Result<Vec<_>> = render_block(&jitsuki_elements)?
+                let jitsuki_lines: Result<Vec<_>> = render_block(&jitsuki_elements, options)?
                     .into_iter()
                     .map(|line| line.extract_components())
                     .collect();
-                lines.last_mut().unwrap().set_jiyose(Jiyose {
+                last_line(&mut lines).set_jiyose(Jiyose {
                     level: 0,
                     lines: jitsuki_lines.context("Failed to render children of jitsuki block")?,
                 })?;
@@ -622,16 +1013,21 @@ pub fn render_block(elements: &[&ParsedRubyTxtElement]) -> Result<Vec<RenderedRu
                     elements = &elements[1..];
                 }
 
-                let jiyose_line = render_line_components(&jiyose_elements)
+                // 字寄せするものがない ("書肆［＃地から３字上げ］" など) ときは何もしない
+                if jiyose_elements.is_empty() {
+                    continue;
+                }
+
+                let jiyose_line = render_line_components(&jiyose_elements, options)
                     .context("Failed to render a line with jiyose")?;
-                lines.last_mut().unwrap().set_jiyose(Jiyose {
+                last_line(&mut lines).set_jiyose(Jiyose {
                     level: *level,
                     lines: vec![jiyose_line],
                 })?;
             }
 
             ParsedRubyTxtElement::JiyoseStartAnnotation { level } => {
-                ensure!(lines.pop().unwrap().is_empty(), "Invalid jiyose-start");
+                ensure!(pop_line(&mut lines).is_empty(), "Invalid jiyose-start");
                 ensure!(
                     matches!(elements.get(1), Some(ParsedRubyTxtElement::NewLine)),
                     "Invalid jiyose-start"
@@ -639,15 +1035,26 @@ pub fn render_block(elements: &[&ParsedRubyTxtElement]) -> Result<Vec<RenderedRu
                 elements = &elements[2..];
 
                 let mut jiyose_elements = Vec::new();
+                let mut closed = false;
                 while !elements.is_empty() {
                     let el = elements[0];
                     elements = &elements[1..];
 
                     if matches!(el, ParsedRubyTxtElement::JiyoseEndAnnotation) {
+                        closed = true;
                         break;
                     }
                     jiyose_elements.push(el);
                 }
+                ensure!(closed, "jiyose block is not closed");
+
+                while let Some(
+                    ParsedRubyTxtElement::AnnotationSource { .. }
+                    | ParsedRubyTxtElement::SourceLine { .. },
+                ) = jiyose_elements.last()
+                {
+                    jiyose_elements.pop();
+                }
 
                 // "［＃ここで字上げ終わり］" 前の改行を取り除く
                 ensure!(
@@ -659,12 +1066,14 @@ pub fn render_block(elements: &[&ParsedRubyTxtElement]) -> Result<Vec<RenderedRu
                 );
 
                 // 地寄せブロックは 1 行につき 1 行
-                for jiyose_line in render_block(&jiyose_elements)? {
+                for jiyose_line in render_block(&jiyose_elements, options)? {
+                    let source_line = jiyose_line.source_line;
                     let jiyose_line = jiyose_line
                         .extract_components()
                         .context("Failed to render children of jiyose block")?;
 
                     let mut line = RenderedRubyTxtLine::new();
+                    line.source_line = source_line;
                     line.set_jiyose(Jiyose {
                         level: *level,
                         lines: vec![jiyose_line],
@@ -691,7 +1100,7 @@ pub fn render_block(elements: &[&ParsedRubyTxtElement]) -> Result<Vec<RenderedRu
                 );
                 elements = &elements[1..];
 
-                let line0 = lines.pop().unwrap();
+                let line0 = pop_line(&mut lines);
                 ensure!(line0.is_blank(true), "Cannot centering page");
 
                 let page_style_1 = match line0.page_style {
@@ -713,7 +1122,7 @@ pub fn render_block(elements: &[&ParsedRubyTxtElement]) -> Result<Vec<RenderedRu
                 style,
             } => {
                 elements = &elements[1..];
-                let line = lines.last_mut().unwrap();
+                let line = last_line(&mut lines);
                 let children = line.pop_last_string(value)?;
 
                 if style == &MidashiStyle::Normal {
@@ -732,13 +1141,111 @@ pub fn render_block(elements: &[&ParsedRubyTxtElement]) -> Result<Vec<RenderedRu
                 });
             }
 
-            _ => {
-                lines
-                    .last_mut()
-                    .unwrap()
-                    .push(RenderedRubyTxtComponent::Tmp {
-                        data: elements[0].clone(),
+            ParsedRubyTxtElement::Kogaki { target, position } => {
+                elements = &elements[1..];
+                let target = target.iter().map(|t| t.text()).collect::<String>();
+                let line = last_line(&mut lines);
+                let children = line.pop_last_string(&target)?;
+
+                line.push(RenderedRubyTxtComponent::Kogaki {
+                    position: position.clone(),
+                    children,
+                });
+            }
+
+            ParsedRubyTxtElement::Caption { value } => {
+                elements = &elements[1..];
+                let value = value.iter().map(|t| t.text()).collect::<String>();
+                let children = last_line(&mut lines).pop_last_string(&value)?;
+                push_caption(&mut lines, children);
+            }
+
+            ParsedRubyTxtElement::CaptionStart => {
+                // 同じ行で閉じていないものは規格外なのでそのまま残す
+                let end = elements.iter().position(|el| {
+                    matches!(
+                        el,
+                        ParsedRubyTxtElement::CaptionEnd | ParsedRubyTxtElement::NewLine
+                    )
+                });
+                let end = match end {
+                    Some(end) if matches!(elements[end], ParsedRubyTxtElement::CaptionEnd) => end,
+                    _ => {
+                        last_line(&mut lines).push(RenderedRubyTxtComponent::Tmp {
+                            data: elements[0].clone(),
+                        });
+                        elements = &elements[1..];
+                        continue;
+                    }
+                };
+
+                let mut caption_elements = elements[1..end].to_vec();
+                elements = &elements[(end + 1)..];
+
+                if let Some(ParsedRubyTxtElement::AnnotationSource { .. }) = caption_elements.last()
+                {
+                    caption_elements.pop();
+                }
+
+                // 図を囲むキャプション
+                let image = caption_elements
+                    .iter()
+                    .position(|el| !matches!(el, ParsedRubyTxtElement::AnnotationSource { .. }))
+                    .and_then(|i| match caption_elements[i] {
+                        ParsedRubyTxtElement::Image { path, alt } => Some((i, path, alt)),
+                        _ => None,
                     });
+                if let Some((i, path, alt)) = image {
+                    caption_elements.drain(..=i);
+                    let caption = if caption_elements.is_empty() {
+                        Vec::new()
+                    } else {
+                        render_line_components(&caption_elements, options)
+                            .context("Failed to render a caption")?
+                    };
+                    last_line(&mut lines).push(RenderedRubyTxtComponent::Figure {
+                        image: FigureImage {
+                            path: path.clone(),
+                            alt: alt.clone(),
+                        },
+                        caption,
+                    });
+                    continue;
+                }
+
+                let children = if caption_elements.is_empty() {
+                    Vec::new()
+                } else {
+                    render_line_components(&caption_elements, options)
+                        .context("Failed to render a caption")?
+                };
+                push_caption(&mut lines, children);
+            }
+
+            ParsedRubyTxtElement::Mojigumi { style } => {
+                last_line(&mut lines).push(RenderedRubyTxtComponent::LayoutHint {
+                    kind: "mojigumi".to_owned(),
+                    value: style.clone(),
+                });
+                elements = &elements[1..];
+            }
+
+            ParsedRubyTxtElement::BouDecoration { .. }
+            | ParsedRubyTxtElement::BouDecorationStart { .. }
+            | ParsedRubyTxtElement::BouDecorationEnd { .. }
+            | ParsedRubyTxtElement::StringDecoration { .. }
+            | ParsedRubyTxtElement::StringDecorationStart { .. }
+            | ParsedRubyTxtElement::StringDecorationEnd { .. }
+                if options.drop_decorations =>
+            {
+                // 対象の文字列は既に行に入っている
+                elements = &elements[1..];
+            }
+
+            _ => {
+                last_line(&mut lines).push(RenderedRubyTxtComponent::Tmp {
+                    data: elements[0].clone(),
+                });
                 elements = &elements[1..];
             }
         }
@@ -754,23 +1261,136 @@ pub fn render_block(elements: &[&ParsedRubyTxtElement]) -> Result<Vec<RenderedRu
     Ok(lines)
 }
 
+// キャプションを追加する
+// 直前が図 (同じ行の末尾か、図のみの前の行) であれば Figure にまとめる
+fn push_caption(lines: &mut Vec<RenderedRubyTxtLine>, caption: Vec<RenderedRubyTxtComponent>) {
+    fn take_image(line: &mut RenderedRubyTxtLine) -> Option<FigureImage> {
+        match line.components.last() {
+            Some(RenderedRubyTxtComponent::Tmp {
+                data: ParsedRubyTxtElement::Image { path, alt },
+            }) => {
+                let image = FigureImage {
+                    path: path.clone(),
+                    alt: alt.clone(),
+                };
+                line.components.pop();
+                Some(image)
+            }
+            _ => None,
+        }
+    }
+
+    let line = last_line(lines);
+    if let Some(image) = take_image(line) {
+        line.push(RenderedRubyTxtComponent::Figure { image, caption });
+        return;
+    }
+
+    let n = lines.len();
+    if lines[n - 1].components.is_empty() && n >= 2 {
+        let prev = &mut lines[n - 2];
+        if prev.components.len() == 1 {
+            if let Some(image) = take_image(prev) {
+                prev.push(RenderedRubyTxtComponent::Figure { image, caption });
+                lines.pop();
+                return;
+            }
+        }
+    }
+
+    last_line(lines).push(RenderedRubyTxtComponent::Caption { children: caption });
+}
+
+// 現在の行
+// 注記のみの行を取り除いた直後は lines が空になりうるので新しい行を作る
+fn last_line(lines: &mut Vec<RenderedRubyTxtLine>) -> &mut RenderedRubyTxtLine {
+    if lines.is_empty() {
+        lines.push(RenderedRubyTxtLine::new());
+    }
+    lines.last_mut().unwrap()
+}
+
+// 現在の行を取り除く
+// lines が空なら空の行とみなす
+fn pop_line(lines: &mut Vec<RenderedRubyTxtLine>) -> RenderedRubyTxtLine {
+    lines.pop().unwrap_or_else(RenderedRubyTxtLine::new)
+}
+
+// ブロックの始まり・終わりの注記の行を取り除く
+// ［＃ここから罫囲み、２字下げ］ のように続けて書かれたものは最後の注記で取り除く
+fn pop_directive_line(
+    lines: &mut Vec<RenderedRubyTxtLine>,
+    rest: &[&ParsedRubyTxtElement],
+) -> RenderedRubyTxtLine {
+    let next = rest
+        .iter()
+        .find(|el| !matches!(el, ParsedRubyTxtElement::AnnotationSource { .. }));
+    let continued = matches!(
+        next,
+        Some(
+            ParsedRubyTxtElement::JisageStartAnnotation { .. }
+                | ParsedRubyTxtElement::JisageWithOrikaeshiStartAnnotation { .. }
+                | ParsedRubyTxtElement::JisageAfterTentsukiStartAnnotation { .. }
+                | ParsedRubyTxtElement::JisageEndAnnotation
+                | ParsedRubyTxtElement::LineWidthStart { .. }
+                | ParsedRubyTxtElement::LineWidthEnd
+                | ParsedRubyTxtElement::KeigakomiStart
+                | ParsedRubyTxtElement::KeigakomiEnd
+        )
+    );
+    if continued {
+        return RenderedRubyTxtLine::new();
+    }
+
+    pop_line(lines)
+}
+
+// ｜ なしのルビが掛かる範囲の開始位置
+// 末尾から同じ文字種が続くところまでとする
+// 絵文字などを 1 文字として扱うため書記素クラスタ単位で見る（バイト単位の index を返す）
+//
+// 旧仮名遣いの作品では 〻 (二の字点) を 々 と同じく漢字に続けて使う (屡〻《しばしば》, 各〻《おのおの》)
+// 新仮名遣いの作品では記号として単独で使われうるので、その場合は文字種の区切りのままとする
+pub(super) fn ruby_base_start(s: &str, old_kana: bool) -> usize {
+    let runs = CharType::runs(s);
+    let Some((_, last)) = runs.last() else {
+        return 0;
+    };
+    if !old_kana {
+        return last.start;
+    }
+
+    let mut start = last.start;
+    for (char_type, range) in runs.iter().rev() {
+        let is_ninojiten = s[range.clone()].chars().all(|c| c == '〻');
+        if *char_type != CharType::Kanji && !is_ninojiten {
+            break;
+        }
+        start = range.start;
+    }
+    start
+}
+
 fn render_line_components(
     elements: &[&ParsedRubyTxtElement],
+    options: &RenderOptions,
 ) -> Result<Vec<RenderedRubyTxtComponent>> {
-    let lines = render_block(elements)?;
+    let mut lines = render_block(elements, options)?;
     ensure!(
         !lines.is_empty(),
-        "Failed to render one-line components: Empty block"
+        "Failed to render one-line components: Empty block: {:?}",
+        elements
     );
     ensure!(
         lines.len() == 1,
-        "Failed to render one-line components: Multiple lines"
+        "Failed to render one-line components: Multiple lines: {:?}",
+        lines
     );
 
-    lines
-        .into_iter()
-        .nth(0)
-        .unwrap()
-        .extract_components()
-        .context("Failed to render one-line components: Failed to extract")
+    lines.pop().unwrap().extract_components().with_context(|| {
+        format!(
+            "Failed to render one-line components: Failed to extract: {:?}",
+            elements
+        )
+    })
 }
@@ -0,0 +1,38 @@
+use crate::ruby_txt::renderer::{RenderedRubyTxt, RenderedRubyTxtComponent};
+
+// 冒頭・本文・末尾にあるルビの (親文字, 読み) をすべて求める
+// 字寄せや見出し・小書きの中も探す
+pub fn ruby_pairs(rendered: &RenderedRubyTxt) -> Vec<(String, String)> {
+    let mut pairs = Vec::new();
+    for lines in [&rendered.header, &rendered.body, &rendered.footer] {
+        for line in lines {
+            collect_ruby_pairs(&line.components, &mut pairs);
+            if let Some(jiyose) = &line.jiyose {
+                for components in &jiyose.lines {
+                    collect_ruby_pairs(components, &mut pairs);
+                }
+            }
+        }
+    }
+    pairs
+}
+
+fn collect_ruby_pairs(components: &[RenderedRubyTxtComponent], pairs: &mut Vec<(String, String)>) {
+    for component in components {
+        match component {
+            RenderedRubyTxtComponent::Ruby { ruby, children } => {
+                let base = children.iter().map(|c| c.text()).collect();
+                let reading = ruby.iter().map(|c| c.text()).collect();
+                pairs.push((base, reading));
+            }
+
+            RenderedRubyTxtComponent::Midashi { children, .. }
+            | RenderedRubyTxtComponent::Kogaki { children, .. }
+            | RenderedRubyTxtComponent::Caption { children } => collect_ruby_pairs(children, pairs),
+            RenderedRubyTxtComponent::Figure { caption, .. } => collect_ruby_pairs(caption, pairs),
+            RenderedRubyTxtComponent::UnknownAnnotation { args } => collect_ruby_pairs(args, pairs),
+
+            _ => {}
+        }
+    }
+}
@@ -2,12 +2,16 @@ use anyhow::{ensure, Context, Result};
 
 use crate::{
     ruby_txt::parser::ParsedRubyTxtElement,
-    ruby_txt::{block_parser::parse_block, tokenizer::RubyTxtToken},
+    ruby_txt::{
+        block_parser::{parse_block, BlockContext},
+        tokenizer::RubyTxtToken,
+    },
 };
 
 // RubyStart ... RubyEnd
 pub(super) fn parse_ruby<'a>(
     tokens: &'a [&'a RubyTxtToken],
+    ctx: &BlockContext,
 ) -> Result<(&'a [&'a RubyTxtToken], Vec<ParsedRubyTxtElement>)> {
     ensure!(matches!(tokens.get(0), Some(RubyTxtToken::RubyStart)));
     let tokens = &tokens[1..];
@@ -31,6 +35,6 @@ pub(super) fn parse_ruby<'a>(
     let child_tokens = &tokens[..end_index];
     let tokens = &tokens[(end_index + 1)..];
 
-    let child_elements = parse_block(&child_tokens)?;
+    let child_elements = parse_block(&child_tokens, &ctx.nested()?)?;
     Ok((tokens, child_elements))
 }
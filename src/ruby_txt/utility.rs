@@ -72,3 +72,24 @@ pub enum StringDecorationStyle {
     Bold,
     Italic,
 }
+
+// 小書き https://www.aozora.gr.jp/annotation/etc.html#kogaki
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum KogakiPosition {
+    LineRight,   // 行右小書き
+    LineLeft,    // 行左小書き
+    Superscript, // 上付き小文字
+    Subscript,   // 下付き小文字
+}
+impl KogakiPosition {
+    pub fn of(name: &str) -> Result<Self> {
+        match name {
+            "行右小書き" => Ok(Self::LineRight),
+            "行左小書き" => Ok(Self::LineLeft),
+            "上付き小文字" => Ok(Self::Superscript),
+            "下付き小文字" => Ok(Self::Subscript),
+            name => bail!("Unknown kogaki position: {}", name),
+        }
+    }
+}
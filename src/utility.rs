@@ -1,4 +1,5 @@
 pub mod date;
 pub mod jis_x_0213;
 pub mod str;
+#[cfg(feature = "zip")]
 pub mod zip;
@@ -1,8 +1,12 @@
-use anyhow::{bail, Result};
+use std::ops::Range;
+
+use anyhow::{bail, Context, Result};
+use encoding_rs::DecoderResult;
+use unicode_segmentation::UnicodeSegmentation;
 
 // 青空文庫に向けた文字種別
 // 仝々〆〇ヶ は漢字扱い (https://www.aozora.gr.jp/annotation/etc.html#ruby)
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CharType {
     LatinAlphabet,
     Hiragana,
@@ -15,7 +19,10 @@ impl CharType {
     pub fn from(c: char) -> Self {
         let u = c as u32;
 
-        if 0x0041 <= u && u <= 0x005a || 0x0061 <= u && u <= 0x007a {
+        // ヶ は片仮名の範囲にあるので先に見る
+        if matches!(c, '仝' | '々' | '〆' | '〇' | 'ヶ') {
+            Self::Kanji
+        } else if 0x0041 <= u && u <= 0x005a || 0x0061 <= u && u <= 0x007a {
             // 小文字・大文字
             Self::LatinAlphabet
         } else if 0x00c0 <= u && u <= 0x00ff && u != 0x00d7 && u != 0x00f7 {
@@ -28,21 +35,60 @@ impl CharType {
         } else if 0x3400 <= u && u <= 0x4dbf
             || 0x4e00 <= u && u <= 0x9fff
             || 0xf900 <= u && u <= 0xfaff
-            || c == '仝'
-            || c == '々'
-            || c == '〆'
-            || c == '〇'
-            || c == 'ヶ'
         {
             Self::Kanji
         } else {
             Self::Other
         }
     }
+
+    // 長音符・濁点・半濁点・踊り字
+    // 平仮名にも片仮名にも使われるので直前の文字種に合わせる
+    fn inherits_preceding(c: char) -> bool {
+        matches!(c, 'ー' | '゛' | '゜' | 'ゝ' | 'ゞ' | 'ヽ' | 'ヾ')
+    }
+
+    // アポストロフィ・ハイフン
+    // 英単語の途中にあるもの (rock'n'roll など) は直前の英字に合わせる
+    fn inherits_preceding_latin(c: char) -> bool {
+        matches!(c, '\'' | '’' | '-')
+    }
+
+    // 同じ文字種が続く範囲に分ける
+    // 範囲はバイト単位で、書記素クラスタの途中では分けない
+    pub fn runs(s: &str) -> Vec<(CharType, Range<usize>)> {
+        let mut runs: Vec<(CharType, Range<usize>)> = Vec::new();
+        for (i, g) in s.grapheme_indices(true) {
+            let char_type = char_type_of_grapheme(g);
+            let first = g.chars().next();
+            let inherits = first.is_some_and(Self::inherits_preceding);
+            let inherits_latin = first.is_some_and(Self::inherits_preceding_latin);
+            match runs.last_mut() {
+                Some((last_type, range))
+                    if *last_type == char_type
+                        || inherits
+                        || (inherits_latin && *last_type == CharType::LatinAlphabet) =>
+                {
+                    range.end = i + g.len()
+                }
+                _ => runs.push((char_type, i..(i + g.len()))),
+            }
+        }
+        runs
+    }
+}
+
+// 書記素クラスタ（絵文字の結合や濁点の合成を含む 1 文字）の文字種別
+// 先頭の文字で判断する
+pub fn char_type_of_grapheme(g: &str) -> CharType {
+    match g.chars().next() {
+        Some(c) => CharType::from(c),
+        None => CharType::Other,
+    }
 }
 
 pub fn parse_number(s: &str) -> Result<usize> {
-    let mut ret = 0;
+    let mut ret: usize = 0;
     for c in s.chars() {
         let zero = match c {
             '0'..='9' => '0',
@@ -52,8 +98,69 @@ pub fn parse_number(s: &str) -> Result<usize> {
 
         let d = (c as usize) - zero;
 
-        ret *= 10;
-        ret += d;
+        ret = ret
+            .checked_mul(10)
+            .and_then(|r| r.checked_add(d))
+            .with_context(|| format!("Too large number: {:?}", s))?;
     }
     Ok(ret)
 }
+
+// 漢数字 (一〜九十九、百) も読む
+// 字下げなどの字数に使われる程度の範囲に限る
+pub fn parse_number_kanji(s: &str) -> Result<usize> {
+    let digit = |c: char| {
+        "一二三四五六七八九"
+            .find(c)
+            .map(|i| i / '一'.len_utf8() + 1)
+    };
+
+    let chars: Vec<_> = s.chars().collect();
+    let ret = match chars.as_slice() {
+        ['百'] => 100,
+        [d] if digit(*d).is_some() => digit(*d).unwrap(),
+        _ => {
+            // (十の位)十(一の位)
+            let Some(i) = chars.iter().position(|&c| c == '十') else {
+                return parse_number(s);
+            };
+            let tens = match &chars[..i] {
+                [] => 1,
+                [d] => digit(*d).with_context(|| format!("Failed to parse {:?}", s))?,
+                _ => bail!("Failed to parse {:?}", s),
+            };
+            let ones = match &chars[(i + 1)..] {
+                [] => 0,
+                [d] => digit(*d).with_context(|| format!("Failed to parse {:?}", s))?,
+                _ => bail!("Failed to parse {:?}", s),
+            };
+            tens * 10 + ones
+        }
+    };
+    Ok(ret)
+}
+
+// Shift_JIS としてデコードする
+// 不正なバイト列があれば U+FFFD に置き換えずにエラーにする
+pub fn decode_shift_jis(bytes: &[u8]) -> Result<String> {
+    let mut decoder = encoding_rs::SHIFT_JIS.new_decoder();
+
+    let capacity = decoder
+        .max_utf8_buffer_length_without_replacement(bytes.len())
+        .context("Too large to decode")?;
+    let mut ret = String::with_capacity(capacity);
+
+    let (result, read) = decoder.decode_to_string_without_replacement(bytes, &mut ret, true);
+    match result {
+        DecoderResult::InputEmpty => Ok(ret),
+        DecoderResult::Malformed(malformed_len, consumed_len) => {
+            let offset = read - (malformed_len as usize) - (consumed_len as usize);
+            bail!(
+                "Invalid Shift_JIS sequence at byte {}: {:02X?}",
+                offset,
+                &bytes[offset..(offset + malformed_len as usize)]
+            )
+        }
+        DecoderResult::OutputFull => bail!("Failed to decode: Output buffer is full"),
+    }
+}
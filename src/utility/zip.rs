@@ -1,22 +1,36 @@
-use std::io::{self, Read};
+use std::{
+    fs::File,
+    io::{self, Read, SeekFrom},
+    path::Path,
+};
 
-use anyhow::{Context, Result};
-use zip::{read::ZipFile, ZipArchive};
+use anyhow::{bail, ensure, Context, Result};
+use zip::{read::ZipFile, CompressionMethod, DateTime, ZipArchive};
+
+use crate::utility::str::decode_shift_jis;
 
 pub struct ZipReader<R> {
     archive: ZipArchive<R>,
 }
 
 impl<R: Read + io::Seek> ZipReader<R> {
-    pub fn new(reader: R) -> Result<ZipReader<R>> {
-        let archive = ZipArchive::new(reader).context("Failed to open")?;
+    pub fn new(mut reader: R) -> Result<ZipReader<R>> {
+        // ZipArchive::new は失敗すると reader を返さないので先に調べておく
+        let diagnosis = diagnose(&mut reader);
+        let archive =
+            ZipArchive::new(reader).with_context(|| format!("Failed to open ({})", diagnosis))?;
         Ok(ZipReader { archive })
     }
 
-    pub fn len(self: &Self) -> usize {
+    pub fn len(&self) -> usize {
         self.archive.len()
     }
 
+    // 順序は zip の中の順とは限らない
+    pub fn file_names(&self) -> impl Iterator<Item = &str> {
+        self.archive.file_names()
+    }
+
     pub fn get_by_path(&mut self, path: &str) -> Result<ZipEntry> {
         self.archive
             .by_name(path)
@@ -30,19 +44,153 @@ impl<R: Read + io::Seek> ZipReader<R> {
             .with_context(|| format!("Failed to open at {}", index))
             .map(|file| ZipEntry { file })
     }
+
+    // 名前を並べ替えて返す
+    pub fn sorted_file_names(&self) -> Vec<&str> {
+        let mut names: Vec<_> = self.file_names().collect();
+        names.sort();
+        names
+    }
+
+    // 作品の .txt を選ぶ (find_txt_entry)
+    pub fn get_txt(&mut self) -> Result<ZipEntry<'_>> {
+        let (index, _) = find_txt_entry(self, true)?;
+        self.get_by_index(index)
+    }
+}
+
+// zip の中の作品の .txt の位置 (get_by_index で開ける) と、
+// .txt が複数あって名前に ruby を含むものを選んだか
+// prefer_ruby なら select_txt と同様に選び、そうでなければ .txt が 1 つだけのときに限る
+pub fn find_txt_entry<R: Read + io::Seek>(
+    zip: &mut ZipReader<R>,
+    prefer_ruby: bool,
+) -> Result<(usize, bool)> {
+    // file_names は zip の中の順でないので 1 つずつ開いて名前を得る
+    let mut names = Vec::with_capacity(zip.len());
+    for index in 0..zip.len() {
+        names.push(zip.get_by_index(index)?.name().to_owned());
+    }
+    let names: Vec<_> = names.iter().map(|n| n.as_str()).collect();
+
+    let index = select_txt_index(&names, prefer_ruby)?;
+    let selected = names.iter().filter(|n| is_txt(n)).count() > 1;
+    Ok((index, selected))
+}
+
+// .txt が複数あるとき (OCR の結果と校正後のものなど) は名前に ruby を含むものを選ぶ
+// ruby を含むものがなければ、.txt が 1 つだけのときに限りそれを選ぶ
+pub fn select_txt<'a>(names: &[&'a str]) -> Result<&'a str> {
+    Ok(names[select_txt_index(names, true)?])
+}
+
+// names の中の作品の .txt の位置
+// prefer_ruby なら select_txt と同様に選び、そうでなければ .txt が 1 つだけのときに限る
+pub fn select_txt_index(names: &[&str], prefer_ruby: bool) -> Result<usize> {
+    let is_ruby = |n: &str| is_txt(n) && n.to_lowercase().contains("ruby");
+
+    let txts: Vec<_> = (0..names.len()).filter(|&i| is_txt(names[i])).collect();
+    let rubies: Vec<_> = if prefer_ruby {
+        (0..names.len()).filter(|&i| is_ruby(names[i])).collect()
+    } else {
+        Vec::new()
+    };
+
+    let names_of = |indices: &[usize]| indices.iter().map(|&i| names[i]).collect::<Vec<_>>();
+    match (rubies.as_slice(), txts.as_slice()) {
+        ([ruby], _) => Ok(*ruby),
+        ([], [txt]) => Ok(*txt),
+        ([], []) => bail!(".txt file is not found"),
+        ([], _) if prefer_ruby => bail!(
+            ".txt file exists more than 1 without ruby: {:?}",
+            names_of(&txts)
+        ),
+        ([], _) => bail!(".txt file exists more than 1: {:?}", names_of(&txts)),
+        _ => bail!(
+            ".txt file with ruby exists more than 1: {:?}",
+            names_of(&rubies)
+        ),
+    }
+}
+
+// 大文字小文字は区別しない
+fn is_txt(name: &str) -> bool {
+    name.to_lowercase().ends_with(".txt")
+}
+
+impl ZipReader<File> {
+    pub fn open(path: &Path) -> Result<ZipReader<File>> {
+        ensure!(path.exists(), "File not found: {}", path.display());
+        let file =
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        ZipReader::new(file).with_context(|| format!("Failed to open {}", path.display()))
+    }
 }
 
+// ファイルの大きさと先頭 4 バイトを調べる
+// 移動した作品の URL からは HTML (リダイレクトのページ) が落ちてくることがある
+fn diagnose<R: Read + io::Seek>(reader: &mut R) -> String {
+    let mut diagnose = || -> io::Result<String> {
+        let size = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(0))?;
+
+        let mut head = Vec::with_capacity(4);
+        reader.by_ref().take(4).read_to_end(&mut head)?;
+        reader.seek(SeekFrom::Start(0))?;
+
+        let kind = match head.as_slice() {
+            b"PK\x03\x04" => "ZIP header",
+            b"<!DO" | b"<!do" | b"<htm" | b"<HTM" => "looks like HTML",
+            [] => "empty",
+            _ => "unknown format",
+        };
+
+        Ok(format!(
+            "size: {} bytes, first bytes: {:02X?}, {}",
+            size, head, kind
+        ))
+    };
+
+    diagnose().unwrap_or_else(|e| format!("failed to diagnose: {}", e))
+}
+
+// as_bytes で先に確保する大きさの上限 (16 MiB)
+const MAX_CAPACITY_HINT: u64 = 16 * 1024 * 1024;
+
 pub struct ZipEntry<'a> {
     file: ZipFile<'a>,
 }
 
 impl ZipEntry<'_> {
-    pub fn name(self: &Self) -> &str {
+    pub fn name(&self) -> &str {
         self.file.name()
     }
 
-    pub fn as_bytes(self: &mut Self) -> Result<Vec<u8>> {
-        let mut data = Vec::<u8>::new();
+    // 展開後のサイズ
+    pub fn size(&self) -> u64 {
+        self.file.size()
+    }
+
+    // 圧縮後のサイズ
+    pub fn compressed_size(&self) -> u64 {
+        self.file.compressed_size()
+    }
+
+    pub fn compression(&self) -> CompressionMethod {
+        self.file.compression()
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.file.is_dir()
+    }
+
+    pub fn last_modified(&self) -> DateTime {
+        self.file.last_modified()
+    }
+
+    pub fn as_bytes(&mut self) -> Result<Vec<u8>> {
+        // サイズは zip に書かれた値なので、信用しすぎないよう上限を設ける
+        let mut data = Vec::<u8>::with_capacity(self.size().min(MAX_CAPACITY_HINT) as usize);
         self.file
             .read_to_end(&mut data)
             .with_context(|| format!("Failed to read {}", self.name()))?;
@@ -50,7 +198,7 @@ impl ZipEntry<'_> {
         Ok(data)
     }
 
-    pub fn as_string(self: &mut Self) -> Result<String> {
+    pub fn as_string(&mut self) -> Result<String> {
         let mut data = String::new();
         self.file
             .read_to_string(&mut data)
@@ -58,4 +206,18 @@ impl ZipEntry<'_> {
 
         Ok(data)
     }
+
+    // 青空文庫の .txt は Shift_JIS
+    pub fn as_shift_jis_string(&mut self) -> Result<String> {
+        let bytes = self.as_bytes()?;
+        decode_shift_jis(&bytes)
+            .with_context(|| format!("Failed to decode {} as Shift_JIS", self.name()))
+    }
+}
+
+// 展開しながら読む
+impl Read for ZipEntry<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
 }
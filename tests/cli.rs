@@ -0,0 +1,40 @@
+#![cfg(feature = "cli")]
+
+use std::process::Command;
+
+// 引数の検査で失敗し、そのメッセージを返す
+fn run_with_invalid_args(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_aozorabunko-json"))
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(!output.status.success(), "{:?}", args);
+    String::from_utf8(output.stderr).unwrap()
+}
+
+#[test]
+fn test_sqlite_conflicts() {
+    for option in [
+        vec!["--streaming"],
+        vec!["--layout", "by-date"],
+        vec!["--format", "msgpack"],
+    ] {
+        let mut args = vec!["--sqlite", "out.db"];
+        args.extend(option);
+        args.push("aozorabunko");
+
+        let stderr = run_with_invalid_args(&args);
+        assert!(
+            stderr.contains("--sqlite cannot be specified with --streaming, --layout or --format"),
+            "{}",
+            stderr
+        );
+    }
+
+    let stderr = run_with_invalid_args(&["--sqlite", "out.db", "aozorabunko", "out"]);
+    assert!(
+        stderr.contains("--sqlite cannot be specified with"),
+        "{}",
+        stderr
+    );
+}
@@ -0,0 +1,250 @@
+#![cfg(feature = "index")]
+
+use aozorabunko_json::list_person_all_extended_csv::parser::{
+    parse_index_list_from_reader, parse_index_list_from_shift_jis,
+    parse_list_person_all_extended_csv, Author, AuthorRole,
+};
+
+fn author(id: usize, last_name_sort_key: &str, first_name_sort_key: &str) -> Author {
+    Author {
+        id,
+        last_name: String::new(),
+        first_name: String::new(),
+        last_name_kana: String::new(),
+        first_name_kana: String::new(),
+        last_name_sort_key: last_name_sort_key.to_owned(),
+        first_name_sort_key: first_name_sort_key.to_owned(),
+        last_name_romaji: String::new(),
+        first_name_romaji: String::new(),
+        birth_date: String::new(),
+        death_date: String::new(),
+        copyright: false,
+    }
+}
+
+#[test]
+fn test_sort_authors() {
+    let mut authors = Vec::from([
+        author(35, "なつめ", "そうせき"),
+        author(879, "あくたかわ", "りゆうのすけ"),
+        author(81, "みやさわ", "けんし"),
+        author(9, "なつめ", "あ"),
+        author(8, "なつめ", "あ"),
+    ]);
+    authors.sort();
+
+    let ids: Vec<_> = authors.iter().map(|a| a.id).collect();
+    assert_eq!(ids, vec![879, 8, 9, 35, 81]);
+
+    // 人物 ID が同じなら他の項目が違っても同じ人物
+    assert_eq!(author(35, "なつめ", "そうせき"), author(35, "", ""));
+}
+
+// 作品 ID, 人物 ID などのみを埋めた 1 行
+fn record(book_id: usize, title: &str, author_id: usize) -> String {
+    record_with_writing_system(book_id, title, author_id, "新字新仮名")
+}
+
+fn record_with_writing_system(
+    book_id: usize,
+    title: &str,
+    author_id: usize,
+    writing_system: &str,
+) -> String {
+    let mut fields = vec![String::new(); 55];
+    fields[0] = book_id.to_string();
+    fields[1] = title.to_owned();
+    fields[9] = writing_system.to_owned();
+    fields[10] = "なし".to_owned();
+    fields[11] = "1999-01-01".to_owned();
+    fields[12] = "2000-01-01".to_owned();
+    fields[14] = author_id.to_string();
+    fields[23] = "著者".to_owned();
+    fields[26] = "なし".to_owned();
+    fields.join(",")
+}
+
+#[test]
+fn test_parse_index_list_from_reader() -> anyhow::Result<()> {
+    let csv = [
+        vec!["header"; 55].join(","),
+        record(1, "作品一", 10),
+        record(2, "作品二", 10),
+    ]
+    .join("\n");
+
+    // 読み込みながら解析しても結果は同じ
+    let list = parse_index_list_from_reader(std::io::Cursor::new(csv.as_bytes()))?;
+    assert_eq!(list.authors.len(), 1);
+    assert_eq!(list.books.len(), 2);
+    assert_eq!(
+        serde_json::to_value(&list)?,
+        serde_json::to_value(parse_list_person_all_extended_csv(&csv)?)?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_parse_index_list_from_shift_jis() -> anyhow::Result<()> {
+    let csv = [
+        vec!["header"; 55].join(","),
+        record(1, "作品一", 10),
+        record(2, "作品二", 10),
+    ]
+    .join("\n");
+    let (sjis, _, unmappable) = encoding_rs::SHIFT_JIS.encode(&csv);
+    assert!(!unmappable);
+
+    let list = parse_index_list_from_shift_jis(&sjis)?;
+    assert_eq!(list.book(2).map(|b| b.title.as_str()), Some("作品二"));
+    assert_eq!(
+        serde_json::to_value(&list)?,
+        serde_json::to_value(parse_list_person_all_extended_csv(&csv)?)?
+    );
+
+    // UTF-8 のまま渡すと読めない
+    assert!(parse_index_list_from_shift_jis(csv.as_bytes()).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_writing_system() -> anyhow::Result<()> {
+    let csv = [
+        vec!["header"; 55].join(","),
+        record_with_writing_system(1, "作品一", 10, "新字新仮名"),
+        record_with_writing_system(2, "作品二", 10, "旧字旧仮名"),
+        record_with_writing_system(3, "作品三", 10, "新字旧仮名"),
+        record_with_writing_system(4, "作品四", 10, "その他"),
+    ]
+    .join("\n");
+    let list = parse_list_person_all_extended_csv(&csv)?;
+
+    let flags: Vec<_> = list
+        .books
+        .iter()
+        .map(|b| {
+            (
+                b.is_classic(),
+                b.uses_old_characters(),
+                b.uses_old_kana(),
+                b.uses_new_kana(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        flags,
+        vec![
+            (false, false, false, true),
+            (true, true, true, false),
+            (false, false, true, false),
+            (false, false, false, false),
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_lookup_by_id() -> anyhow::Result<()> {
+    let csv = [
+        vec!["header"; 55].join(","),
+        record(1, "作品一", 10),
+        record(2, "作品二", 10),
+        record(2, "作品二", 20),
+    ]
+    .join("\n");
+    let list = parse_list_person_all_extended_csv(&csv)?;
+
+    assert_eq!(list.book(2).map(|b| b.title.as_str()), Some("作品二"));
+    assert!(list.book(3).is_none());
+    assert_eq!(list.author(20).map(|a| a.id), Some(20));
+    assert!(list.author(30).is_none());
+
+    let ids: Vec<_> = list.authors_of(2).iter().map(|a| a.id).collect();
+    assert_eq!(ids, vec![10, 20]);
+    assert!(list.authors_of(3).is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_iter_books() -> anyhow::Result<()> {
+    let csv = [
+        vec!["header"; 55].join(","),
+        record(3, "作品三", 30),
+        record(1, "作品一", 10),
+        record(2, "作品二", 20),
+        record(2, "作品二", 10),
+    ]
+    .join("\n");
+    let list = parse_list_person_all_extended_csv(&csv)?;
+
+    let books: Vec<_> = list
+        .iter_books()
+        .map(|bwa| {
+            let authors: Vec<_> = bwa
+                .authors
+                .iter()
+                .map(|(author, role)| (author.id, role.clone()))
+                .collect();
+            (bwa.book.id, authors)
+        })
+        .collect();
+    assert_eq!(
+        books,
+        vec![
+            (1, vec![(10, AuthorRole::Author)]),
+            (2, vec![(10, AuthorRole::Author), (20, AuthorRole::Author)]),
+            (3, vec![(30, AuthorRole::Author)]),
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_txt_zip_local_path() -> anyhow::Result<()> {
+    let txt_urls = [
+        "https://www.aozora.gr.jp/cards/000081/files/43737_ruby_19028.zip",
+        "http://www.aozora.gr.jp/cards/000081/files/43737_ruby_19028.zip",
+        "https://www.aozora.gr.jp/cards/000081/files/43737_ruby_19028.zip?v=1",
+        "https://example.com/cards/000081/files/43737_ruby_19028.zip",
+        "https://www.aozora.gr.jp/cards/000081/files/43737_19028.html",
+        "https://www.aozora.gr.jp/cards/../../43737_ruby_19028.zip",
+    ];
+
+    let mut csv = vec![vec!["header"; 55].join(",")];
+    for (i, txt_url) in txt_urls.iter().enumerate() {
+        let mut fields: Vec<_> = record(i + 1, "作品", 10)
+            .split(',')
+            .map(str::to_owned)
+            .collect();
+        fields[45] = txt_url.to_string();
+        csv.push(fields.join(","));
+    }
+    let list = parse_list_person_all_extended_csv(&csv.join("\n"))?;
+
+    let root = std::path::Path::new("/aozorabunko");
+    let paths: Vec<_> = list
+        .books
+        .iter()
+        .map(|b| b.txt_zip_local_path(root))
+        .collect();
+
+    let expected = root.join("cards/000081/files/43737_ruby_19028.zip");
+    assert_eq!(
+        paths,
+        vec![
+            Some(expected.clone()),
+            Some(expected.clone()),
+            Some(expected),
+            None,
+            None,
+            None,
+        ]
+    );
+
+    Ok(())
+}
@@ -0,0 +1,135 @@
+use std::panic;
+
+use aozorabunko_json::ruby_txt::{
+    parser::parse_ruby_txt, renderer::render_ruby_txt, tokenizer::tokenize_ruby_txt,
+};
+
+// パース・レンダリングはエラーを返すことはあってもパニックしない
+fn assert_no_panic(txt: &str) {
+    let result = panic::catch_unwind(|| {
+        let Ok(tokens) = tokenize_ruby_txt(txt) else {
+            return;
+        };
+        let Ok(parsed) = parse_ruby_txt(&tokens) else {
+            return;
+        };
+        let _ = render_ruby_txt(&parsed);
+    });
+    assert!(result.is_ok(), "Panicked on {:?}", txt);
+}
+
+fn txt_of_body(body: &str) -> String {
+    format!(
+        "作品名\r\n著者名\r\n\r\n{}\r\n\r\n底本：「底本」出版社\r\n",
+        body
+    )
+}
+
+#[test]
+fn test_no_panic_on_whole_txt() {
+    let inputs = [
+        "",
+        "\r\n",
+        "\r\n\r\n",
+        "作品名",
+        "作品名\r\n",
+        "作品名\r\n\r\n",
+        "作品名\r\n\r\n本文",
+        "作品名\r\n\r\n底本：",
+        "作品名\r\n\r\n\r\n\r\n底本：",
+        "［＃改ページ］",
+        "［＃改ページ］\r\n\r\n［＃改ページ］\r\n\r\n底本：",
+        "------\r\n\r\n------",
+        "作品名\r\n\r\n------\r\n------\r\n底本：",
+    ];
+    for input in inputs {
+        assert_no_panic(input);
+    }
+}
+
+#[test]
+fn test_no_panic_on_body() {
+    let bodies = [
+        // 注記のみ
+        "［＃］",
+        "［＃改ページ］",
+        "［＃改丁］［＃改ページ］",
+        "［＃ページの左右中央］",
+        "［＃ここから２字下げ］",
+        "［＃ここで字下げ終わり］",
+        "［＃ここから地付き］",
+        "［＃ここで地付き終わり］",
+        "［＃ここから地から２字上げ］",
+        "［＃地付き］",
+        "［＃地から２字上げ］",
+        "［＃２字下げ］",
+        "［＃大見出し］［＃大見出し終わり］",
+        "［＃「」は大見出し］",
+        "［＃「あ」は大見出し］",
+        "［＃「」に傍点］",
+        "［＃「」］",
+        "［＃「］",
+        "［＃」］",
+        "［＃「」の注記］",
+        "［＃「」に「」の注記］",
+        "［＃（）］",
+        "［＃文字組み］",
+        "［＃改行天付き］",
+        "［＃９９９９９９９９９９９９９９９９９９９９９９９９字下げ］あ",
+        "［＃ここから１８４４６７４４０７３７０９５５１６１５字下げ］\r\n［＃１８４４６７４４０７３７０９５５１６１５字下げ］あ",
+        "［＃ここから２字下げ］あ",
+        "［＃ここで字下げ終わり］あ",
+        "［＃ここから２字下げ、折り返して３字下げ］あ",
+        "［＃ここから改行天付き、折り返して３字下げ］あ",
+        "［＃ここから地付き］\r\nあ\r\n［＃ここで地付き終わり］あ",
+        "［＃ここから地から２字上げ］\r\nあ\r\n［＃ここで字上げ終わり］あ",
+        "［＃ここから２字下げ］［＃２字下げ］",
+        "［＃ここから２字下げ］［＃地付き］あ",
+        "［＃ここから２字下げ］［＃ページの左右中央］\r\nあ",
+        "［＃ここから２字下げ］［＃改ページ］\r\nあ",
+        "［＃ここから２字下げ］《あ》",
+        "［＃ここから２字下げ］｜あ《い》",
+        "［＃ここから２字下げ］［＃「あ」は大見出し］",
+        "あ［＃「あ」は大見出し］［＃「あ」は大見出し］",
+        "漢字《かんじ》［＃「字」は中見出し］",
+        // 入れ子
+        "［＃［＃［＃改ページ］］］",
+        "［＃「［＃「あ」に傍点］」に傍点］",
+        "［＃※［＃※［＃］］］",
+        "※［＃※［＃※［＃］］］",
+        "※［＃［＃］］",
+        "〔〔〔e'〕〕〕",
+        "《《《あ》》》",
+        "｜｜｜《あ》",
+        // 閉じていない
+        "［＃",
+        "［＃改ページ",
+        "※［＃",
+        "〔",
+        "〔e'",
+        "《",
+        "《あ",
+        "｜",
+        "｜あ",
+        // 開いていない
+        "］",
+        "》",
+        "〕",
+        // ルビ
+        "《あ》",
+        "《》",
+        "｜《あ》",
+        "あ｜《い》",
+        "［＃改ページ］《あ》",
+        "［＃２字下げ］《あ》",
+        "※［＃「未知」、1-1-1］《あ》",
+        // くの字点
+        "／＼",
+        "／″＼",
+        "／",
+        "／″",
+    ];
+    for body in bodies {
+        assert_no_panic(&txt_of_body(body));
+    }
+}
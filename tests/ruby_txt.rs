@@ -1,16 +1,41 @@
-use std::fs;
+use std::{
+    env, fs,
+    path::Path,
+    sync::{Arc, Mutex},
+};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 
 use aozorabunko_json::ruby_txt::{
-    parser::parse_ruby_txt, renderer::render_ruby_txt, tokenizer::tokenize_ruby_txt,
+    decoration_span::{all_bou_decoration_spans, all_string_decoration_spans},
+    diff::{diff_parsed_body, DiffItem},
+    footer_metadata::parse_footer_metadata,
+    footnote::resolve_footnotes,
+    header_metadata::{extract_header_meta, HeaderMeta},
+    image::collect_all_images,
+    parser::{
+        parse_header_only, parse_ruby_txt, parse_ruby_txt_with_options, ParseOptions, ParseWarning,
+        ParsedRubyTxt, ParsedRubyTxtElement,
+    },
+    plain_text::{export_plain_text, RubyMode},
+    renderer::{
+        render_ruby_txt, render_ruby_txt_combined, render_ruby_txt_with_options, Jisage, Jiyose,
+        PageStyle, RenderOptions, RenderedRubyTxt, RenderedRubyTxtComponent, RenderedRubyTxtLine,
+    },
+    ruby_pair::ruby_pairs,
+    tokenizer::{tokenize_ruby_txt, RubyTxtToken},
+    utility::{
+        BouDecorationSide, BouDecorationStyle, KogakiPosition, MidashiLevel, MidashiStyle,
+        StringDecorationStyle,
+    },
 };
 
 static RUBY_TXT_SUFFIX: &str = ".ruby.txt";
 
+// tests/*.ruby.txt (作品全体) はパース・レンダリングできることだけを確かめ、結果を *.json に書き出す
 #[test]
 fn test_ruby_txt_all() -> Result<()> {
-    let paths = fs::read_dir("./tests").unwrap();
+    let paths = fs::read_dir("./tests")?;
     for path in paths {
         let path = path.unwrap().path();
         let file_name = path.file_name().unwrap().to_str().unwrap();
@@ -39,3 +64,2143 @@ fn test_ruby_txt_all() -> Result<()> {
 
     Ok(())
 }
+
+// tests/fixtures の各 *.ruby.txt (注記ごとの最小の例) について、
+// パース・レンダリングの結果をコミット済みの *_parsed.snap, *_rendered.snap と比較する
+// UPDATE_SNAPSHOTS=1 のときは比較せずに書き換える
+#[test]
+fn test_ruby_txt_fixtures() -> Result<()> {
+    let paths = fs::read_dir("./tests/fixtures")?;
+    for path in paths {
+        let path = path.unwrap().path();
+        let file_name = path.file_name().unwrap().to_str().unwrap();
+        if !file_name.ends_with(RUBY_TXT_SUFFIX) {
+            continue;
+        }
+
+        let file_stem = &file_name[..(file_name.len() - RUBY_TXT_SUFFIX.len())];
+
+        let txt = fs::read_to_string(&path).unwrap();
+
+        let content = tokenize_ruby_txt(&txt)?;
+
+        let content = parse_ruby_txt(&content)?;
+        assert_snapshot(
+            &path.with_file_name(format!("{}_parsed.snap", file_stem)),
+            &serde_json::to_string(&content)?,
+        )?;
+
+        let content = render_ruby_txt(&content)?;
+        assert_snapshot(
+            &path.with_file_name(format!("{}_rendered.snap", file_stem)),
+            &serde_json::to_string(&content)?,
+        )?;
+    }
+
+    Ok(())
+}
+
+// レンダリングの結果を JSON にして読み戻すと元に戻る
+#[test]
+fn test_rendered_roundtrip() -> Result<()> {
+    let paths = fs::read_dir("./tests")?.chain(fs::read_dir("./tests/fixtures")?);
+    for path in paths {
+        let path = path.unwrap().path();
+        let file_name = path.file_name().unwrap().to_str().unwrap();
+        if !file_name.ends_with(RUBY_TXT_SUFFIX) {
+            continue;
+        }
+
+        let txt = fs::read_to_string(&path).unwrap();
+        let rendered = render_ruby_txt(&parse_ruby_txt(&tokenize_ruby_txt(&txt)?)?)?;
+
+        let json = serde_json::to_string(&rendered)?;
+        let deserialized: RenderedRubyTxt = serde_json::from_str(&json)
+            .with_context(|| format!("Failed to deserialize {}", file_name))?;
+        assert_eq!(serde_json::to_string(&deserialized)?, json, "{}", file_name);
+        assert_eq!(deserialized, rendered, "{}", file_name);
+    }
+
+    Ok(())
+}
+
+// --format msgpack と同じく書き出して読み戻すと元に戻る
+#[cfg(feature = "cli")]
+#[test]
+fn test_rendered_msgpack_roundtrip() -> Result<()> {
+    let paths = fs::read_dir("./tests")?.chain(fs::read_dir("./tests/fixtures")?);
+    for path in paths {
+        let path = path.unwrap().path();
+        let file_name = path.file_name().unwrap().to_str().unwrap();
+        if !file_name.ends_with(RUBY_TXT_SUFFIX) {
+            continue;
+        }
+
+        let txt = fs::read_to_string(&path).unwrap();
+        let rendered = render_ruby_txt(&parse_ruby_txt(&tokenize_ruby_txt(&txt)?)?)?;
+
+        let msgpack = rmp_serde::to_vec_named(&rendered)?;
+        let deserialized: RenderedRubyTxt = rmp_serde::from_slice(&msgpack)
+            .with_context(|| format!("Failed to deserialize {}", file_name))?;
+        assert_eq!(deserialized, rendered, "{}", file_name);
+    }
+
+    Ok(())
+}
+
+// kind は JSON の "type" と一致する
+#[test]
+fn test_element_kind() -> Result<()> {
+    let paths = fs::read_dir("./tests")?.chain(fs::read_dir("./tests/fixtures")?);
+    for path in paths {
+        let path = path.unwrap().path();
+        let file_name = path.file_name().unwrap().to_str().unwrap();
+        if !file_name.ends_with(RUBY_TXT_SUFFIX) {
+            continue;
+        }
+
+        let txt = fs::read_to_string(&path).unwrap();
+        let parsed = parse_ruby_txt(&tokenize_ruby_txt(&txt)?)?;
+        for el in parsed
+            .header
+            .iter()
+            .chain(&parsed.body)
+            .chain(&parsed.footer)
+        {
+            assert_eq!(
+                serde_json::to_value(el)?["type"],
+                el.kind(),
+                "{}",
+                file_name
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn assert_snapshot(path: &Path, actual: &str) -> Result<()> {
+    if env::var("UPDATE_SNAPSHOTS").is_ok_and(|v| v == "1") {
+        fs::write(path, actual)?;
+        return Ok(());
+    }
+
+    let expected = fs::read_to_string(path)
+        .with_context(|| format!("Snapshot not found: {}", path.display()))?;
+    if expected == actual {
+        return Ok(());
+    }
+
+    // 最初に異なる位置の前後を示す
+    let position = expected
+        .char_indices()
+        .zip(actual.chars())
+        .find(|((_, e), a)| e != a)
+        .map(|((i, _), _)| i)
+        .unwrap_or(expected.len().min(actual.len()));
+    let around = |s: &str| -> String {
+        let start = s[..position.min(s.len())]
+            .char_indices()
+            .rev()
+            .nth(40)
+            .map_or(0, |(i, _)| i);
+        s[start..].chars().take(80).collect()
+    };
+    panic!(
+        "Snapshot mismatch: {} (byte {})\n  expected: {}\n  actual:   {}\nRun with UPDATE_SNAPSHOTS=1 to update",
+        path.display(),
+        position,
+        around(&expected),
+        around(actual)
+    );
+}
+
+#[test]
+fn test_footer_teihon_shoshutsu() -> Result<()> {
+    let txt = "作品名\r\n著者名\r\n\r\n本文\r\n\r\n底本・初出：「雑誌」出版社\r\n";
+
+    let tokens = tokenize_ruby_txt(txt)?;
+    let parsed = parse_ruby_txt(&tokens)?;
+
+    assert!(matches!(
+        parsed.footer.first(),
+        Some(ParsedRubyTxtElement::String { value }) if value.starts_with("底本・初出：")
+    ));
+
+    Ok(())
+}
+
+// 本文のみを与えて冒頭と末尾を補う
+fn txt_of_body(body: &str) -> String {
+    format!(
+        "作品名\r\n著者名\r\n\r\n{}\r\n\r\n底本：「底本」出版社\r\n",
+        body
+    )
+}
+
+fn parse_body(body: &str) -> Result<Vec<ParsedRubyTxtElement>> {
+    let tokens = tokenize_ruby_txt(&txt_of_body(body))?;
+    let parsed = parse_ruby_txt(&tokens)?;
+
+    Ok(parsed.body)
+}
+
+fn render_body(body: &str) -> Result<serde_json::Value> {
+    let tokens = tokenize_ruby_txt(&txt_of_body(body))?;
+    let parsed = parse_ruby_txt(&tokens)?;
+    let rendered = render_ruby_txt(&parsed)?;
+
+    Ok(serde_json::to_value(&rendered)?)
+}
+
+#[test]
+fn test_kaigyo_tentsuki() -> Result<()> {
+    // 単独
+    let rendered = render_body(
+        "［＃ここから２字下げ］\r\n［＃改行天付き］本文\r\n本文\r\n［＃ここで字下げ終わり］",
+    )?;
+    assert_eq!(
+        rendered["body"][0]["jisage"],
+        serde_json::json!({ "level0": 0, "level1": 2 })
+    );
+    assert_eq!(
+        rendered["body"][1]["jisage"],
+        serde_json::json!({ "level0": 2, "level1": 2 })
+    );
+
+    // 折り返しと組み合わせたもの
+    let rendered = render_body(
+        "［＃ここから改行天付き、折り返して３字下げ］\r\n本文\r\n［＃ここで字下げ終わり］",
+    )?;
+    assert_eq!(
+        rendered["body"][0]["jisage"],
+        serde_json::json!({ "level0": 0, "level1": 3 })
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_unclosed_block() -> Result<()> {
+    // 字下げは終わりがなくてもブロックの終わりまで続く
+    let rendered = render_body("［＃ここから２字下げ］\r\n本文\r\n本文")?;
+    for line in rendered["body"].as_array().unwrap() {
+        assert_eq!(
+            line["jisage"],
+            serde_json::json!({ "level0": 2, "level1": 2 })
+        );
+    }
+
+    // 地付き・地寄せは終わりがなければエラー
+    assert!(render_body("［＃ここから地付き］\r\n本文\r\n本文").is_err());
+    assert!(render_body("［＃ここから地から２字上げ］\r\n本文\r\n本文").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_editorial_note() -> Result<()> {
+    let body = parse_body("鈔本［＃「鈔本」に「抄本」の注記］")?;
+    assert_eq!(
+        body[1],
+        ParsedRubyTxtElement::EditorialNote {
+            target: vec![ParsedRubyTxtElement::String {
+                value: "鈔本".to_owned()
+            }],
+            note: "抄本".to_owned(),
+        }
+    );
+
+    let body = parse_body("鈔本［＃「鈔本」の注記：原文のまま］")?;
+    assert_eq!(
+        body[1],
+        ParsedRubyTxtElement::EditorialNote {
+            target: vec![ParsedRubyTxtElement::String {
+                value: "鈔本".to_owned()
+            }],
+            note: "原文のまま".to_owned(),
+        }
+    );
+
+    // 「ママ」の注記は従来どおり無視する
+    let body = parse_body("鈔本［＃「鈔本」に「ママ」の注記］")?;
+    assert_eq!(body.len(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn test_metadata_only() -> Result<()> {
+    let txt = txt_of_body("本文［＃「本文」は太字］");
+
+    let tokens = tokenize_ruby_txt(&txt)?;
+    let options = ParseOptions {
+        metadata_only: true,
+        ..Default::default()
+    };
+    let parsed = parse_ruby_txt_with_options(&tokens, &options)?;
+
+    assert!(parsed.body.is_empty());
+    assert_eq!(parsed.header, parse_ruby_txt(&tokens)?.header);
+    assert_eq!(parsed.footer, parse_ruby_txt(&tokens)?.footer);
+
+    Ok(())
+}
+
+#[test]
+fn test_deeply_nested_annotation() -> Result<()> {
+    let depth = 10000;
+    let body = format!("{}あ{}", "［＃".repeat(depth), "］".repeat(depth));
+
+    // スタックが溢れずにエラーになる
+    let err = parse_body(&body).unwrap_err();
+    assert!(format!("{:?}", err).contains("Too deeply nested"));
+
+    // 上限は ParseOptions で変えられる
+    let tokens = tokenize_ruby_txt(&txt_of_body("あ［＃「あ」に傍点］"))?;
+    for (max_nesting_depth, ok) in [(0, false), (1, true)] {
+        let options = ParseOptions {
+            max_nesting_depth,
+            ..Default::default()
+        };
+        assert_eq!(parse_ruby_txt_with_options(&tokens, &options).is_ok(), ok);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_ruby_with_multiple_elements() -> Result<()> {
+    // 読みに外字を含む
+    let body = parse_body("漢字《かん※［＃「麾−毛」、42-8］》")?;
+    assert!(matches!(
+        &body[1],
+        ParsedRubyTxtElement::Ruby { value } if value.len() == 1
+    ));
+
+    // 読みに注記を含む
+    let body = parse_body("漢字《かん［＃「かん」に傍点］じ》")?;
+    let ParsedRubyTxtElement::Ruby { value } = &body[1] else {
+        panic!("Not ruby: {:?}", body);
+    };
+    assert_eq!(value.len(), 3);
+    assert!(matches!(
+        value[1],
+        ParsedRubyTxtElement::BouDecoration { .. }
+    ));
+    render_body("漢字《かん［＃「かん」に傍点］じ》")?;
+
+    Ok(())
+}
+
+#[test]
+fn test_string_decoration_with_target() -> Result<()> {
+    let target = vec![ParsedRubyTxtElement::String {
+        value: "強い".to_owned(),
+    }];
+    for (body, style) in [
+        ("強い［＃「強い」は太字］", StringDecorationStyle::Bold),
+        ("強い［＃「強い」は斜体］", StringDecorationStyle::Italic),
+    ] {
+        assert_eq!(
+            parse_body(body)?[1],
+            ParsedRubyTxtElement::StringDecoration {
+                target: target.clone(),
+                style,
+            }
+        );
+    }
+
+    assert_eq!(
+        parse_body("強い［＃「強い」はキャプション］")?[1],
+        ParsedRubyTxtElement::Caption { value: target }
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_kogaki() -> Result<()> {
+    let body = parse_body("x2［＃「2」は上付き小文字］")?;
+    assert_eq!(
+        body[1],
+        ParsedRubyTxtElement::Kogaki {
+            target: vec![ParsedRubyTxtElement::String {
+                value: "2".to_owned()
+            }],
+            position: KogakiPosition::Superscript,
+        }
+    );
+
+    let body = parse_body("［＃行右小書き］注［＃行右小書き終わり］")?;
+    assert_eq!(
+        body[0],
+        ParsedRubyTxtElement::KogakiStart {
+            position: KogakiPosition::LineRight,
+        }
+    );
+    assert_eq!(
+        body[2],
+        ParsedRubyTxtElement::KogakiEnd {
+            position: KogakiPosition::LineRight,
+        }
+    );
+
+    let rendered = render_body("x2［＃「2」は上付き小文字］")?;
+    let components = &rendered["body"][0]["components"];
+    assert_eq!(components[0]["value"], "x");
+    assert_eq!(components[1]["type"], "kogaki");
+    assert_eq!(components[1]["position"], "superscript");
+
+    Ok(())
+}
+
+#[test]
+fn test_block_separator() -> Result<()> {
+    for c in ['-', '━', '—', '―', '─'] {
+        let separator = c.to_string().repeat(20);
+        let body = parse_body(&format!("前\r\n{}\r\n後", separator))?;
+        assert_eq!(
+            body,
+            vec![
+                ParsedRubyTxtElement::String {
+                    value: "前".to_owned()
+                },
+                ParsedRubyTxtElement::NewLine,
+                ParsedRubyTxtElement::KaipageAttention,
+                ParsedRubyTxtElement::NewLine,
+                ParsedRubyTxtElement::String {
+                    value: "後".to_owned()
+                },
+            ],
+            "separator: {}",
+            separator
+        );
+    }
+
+    // 本文中のダッシュだけの短い行は区切りとしない
+    for line in ["――", "――――――――", "--"] {
+        let body = parse_body(&format!("前\r\n{}\r\n後", line))?;
+        assert!(
+            !body.contains(&ParsedRubyTxtElement::KaipageAttention),
+            "line: {}",
+            line
+        );
+    }
+
+    // 区切りとみなす文字と最小の文字数は ParseOptions で変えられる
+    let tokens = tokenize_ruby_txt(&txt_of_body(&format!("前\r\n{}\r\n後", "━".repeat(20))))?;
+    let options = ParseOptions {
+        separator_chars: vec!['-'],
+        ..Default::default()
+    };
+    let parsed = parse_ruby_txt_with_options(&tokens, &options)?;
+    assert!(!parsed
+        .body
+        .contains(&ParsedRubyTxtElement::KaipageAttention));
+
+    let tokens = tokenize_ruby_txt(&txt_of_body("前\r\n――\r\n後"))?;
+    let options = ParseOptions {
+        separator_min_len: 2,
+        ..Default::default()
+    };
+    let parsed = parse_ruby_txt_with_options(&tokens, &options)?;
+    assert!(parsed
+        .body
+        .contains(&ParsedRubyTxtElement::KaipageAttention));
+
+    // 行の一部になっているものは区切りとしない
+    let separator = "―".repeat(10);
+    for body in [
+        format!("漢字《かんじ》{}", separator),
+        format!("漢字《かんじ》{}続き\r\n後", separator),
+        format!("［＃５字下げ］{}", separator),
+        format!("前\r\n［＃５字下げ］{}\r\n後", separator),
+    ] {
+        let parsed = parse_body(&body)?;
+        assert!(
+            !parsed.contains(&ParsedRubyTxtElement::KaipageAttention),
+            "body: {}",
+            body
+        );
+    }
+
+    // 本文の末尾の区切りの後に空行しかなくてもよい
+    let body = parse_body(&format!("前\r\n{}", separator))?;
+    assert_eq!(
+        body,
+        vec![ParsedRubyTxtElement::String {
+            value: "前".to_owned()
+        }]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_footnote() -> Result<()> {
+    let txt = "作品名\r\n著者名\r\n\r\n本文［＃注１］と本文［＃注２］\r\n\r\n底本：「底本」出版社\r\n注１　一つ目の注\r\n";
+    let tokens = tokenize_ruby_txt(txt)?;
+    let parsed = parse_ruby_txt(&tokens)?;
+
+    assert_eq!(
+        parsed.body[1],
+        ParsedRubyTxtElement::FootnoteRef {
+            id: "１".to_owned()
+        }
+    );
+
+    let footnotes = resolve_footnotes(&parsed);
+    assert_eq!(footnotes.len(), 1);
+    assert_eq!(footnotes[0].0.index, 1);
+    assert_eq!(
+        footnotes[0].1,
+        vec![ParsedRubyTxtElement::String {
+            value: "一つ目の注".to_owned()
+        }]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_ruby_base_with_long_vowel_mark() -> Result<()> {
+    let rendered = render_body("漢字あー《あ》")?;
+    let components = &rendered["body"][0]["components"];
+    assert_eq!(components[0]["value"], "漢字");
+    assert_eq!(components[1]["type"], "ruby");
+    assert_eq!(components[1]["children"][0]["value"], "あー");
+
+    Ok(())
+}
+
+#[test]
+fn test_lenient_page_breaks() -> Result<()> {
+    let options = ParseOptions {
+        lenient_page_breaks: true,
+        ..Default::default()
+    };
+    let render = |body: &str, options: &ParseOptions| -> Result<serde_json::Value> {
+        let tokens = tokenize_ruby_txt(&txt_of_body(body))?;
+        let parsed = parse_ruby_txt_with_options(&tokens, options)?;
+        Ok(serde_json::to_value(render_ruby_txt(&parsed)?)?)
+    };
+
+    for (annotation, page_style) in [
+        ("改丁", "kaicho"),
+        ("改ページ", "kaipage"),
+        ("改段", "kaidan"),
+    ] {
+        // 行頭
+        let body = format!("前\r\n［＃{}］\r\n後", annotation);
+        let rendered = render(&body, &options)?;
+        assert_eq!(rendered, render(&body, &ParseOptions::default())?);
+
+        // 行の途中
+        let body = format!("前［＃{}］後", annotation);
+        assert!(render(&body, &ParseOptions::default()).is_err());
+        let rendered = render(&body, &options)?;
+        let lines = rendered["body"].as_array().unwrap();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["components"][0]["value"], "前");
+        assert!(lines[1]["page-style"][page_style].is_object());
+        assert_eq!(lines[1]["components"][0]["value"], "後");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_on_unknown_annotation() -> Result<()> {
+    let options = ParseOptions {
+        on_unknown_annotation: Some(Arc::new(|args| match args {
+            [ParsedRubyTxtElement::String { value }] if value == "独自の注記" => {
+                Some(ParsedRubyTxtElement::PageCenterAnnotation)
+            }
+            _ => None,
+        })),
+        ..Default::default()
+    };
+
+    let tokens = tokenize_ruby_txt(&txt_of_body("［＃独自の注記］［＃謎の注記］"))?;
+    let parsed = parse_ruby_txt_with_options(&tokens, &options)?;
+    assert_eq!(parsed.body[0], ParsedRubyTxtElement::PageCenterAnnotation);
+    assert!(matches!(
+        parsed.body[1],
+        ParsedRubyTxtElement::UnknownAnnotation { .. }
+    ));
+
+    // 複製しても同じ関数を使う
+    let cloned = options.clone();
+    let parsed = parse_ruby_txt_with_options(&tokens, &cloned)?;
+    assert_eq!(parsed.body[0], ParsedRubyTxtElement::PageCenterAnnotation);
+    let debug = format!("{:?}", cloned);
+    assert!(debug.contains("on_unknown_annotation: Some(\"<fn>\")"));
+    assert!(debug.contains("on_warning: None"));
+
+    Ok(())
+}
+
+#[test]
+fn test_collect_all_images() -> Result<()> {
+    let txt = txt_of_body(
+        "［＃挿絵（fig1_1.png）入る］\r\n［＃キャプション］［＃図（fig1_2.png）入る］［＃キャプション終わり］",
+    );
+    let tokens = tokenize_ruby_txt(&txt)?;
+    let parsed = parse_ruby_txt(&tokens)?;
+
+    assert_eq!(
+        collect_all_images(&parsed),
+        vec![("fig1_1.png", "挿絵"), ("fig1_2.png", "図")]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_position_marker_before_ruby() -> Result<()> {
+    // 與謝野寛、與謝野晶子「巴里より」
+    let rendered = render_body("一番向｜《むか》うにある")?;
+    assert_eq!(
+        rendered["body"][0]["components"],
+        serde_json::json!([
+            { "type": "string", "value": "一番" },
+            {
+                "type": "ruby",
+                "ruby": [{ "type": "string", "value": "むか" }],
+                "children": [{ "type": "string", "value": "向" }],
+            },
+            { "type": "string", "value": "うにある" },
+        ])
+    );
+
+    // 梅崎春生「幻化」
+    let rendered = render_body("「もう一杯｜《く》呉れ」")?;
+    assert_eq!(
+        rendered["body"][0]["components"],
+        serde_json::json!([
+            { "type": "string", "value": "「もう一" },
+            {
+                "type": "ruby",
+                "ruby": [{ "type": "string", "value": "く" }],
+                "children": [{ "type": "string", "value": "杯" }],
+            },
+            { "type": "string", "value": "呉れ」" },
+        ])
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_line_width() -> Result<()> {
+    let body = parse_body("［＃ここから２０字詰め］\r\n詩\r\n［＃ここで字詰め終わり］")?;
+    assert_eq!(body[0], ParsedRubyTxtElement::LineWidthStart { chars: 20 });
+    assert_eq!(body[4], ParsedRubyTxtElement::LineWidthEnd);
+
+    // 字下げと同じく半角数字・漢数字でも書ける
+    for chars in ["20", "二十"] {
+        let body = parse_body(&format!(
+            "［＃ここから{}字詰め］\r\n詩\r\n［＃ここで字詰め終わり］",
+            chars
+        ))?;
+        assert_eq!(body[0], ParsedRubyTxtElement::LineWidthStart { chars: 20 });
+    }
+
+    // 字下げと組み合わせる
+    let rendered = render_body(
+        "前\r\n［＃ここから２字下げ］\r\n［＃ここから２０字詰め］\r\n詩の一行目\r\n詩の二行目\r\n［＃ここで字詰め終わり］\r\n［＃ここで字下げ終わり］\r\n後",
+    )?;
+    let lines = rendered["body"].as_array().unwrap();
+    assert_eq!(lines.len(), 4);
+    assert!(lines[0].get("line-width").is_none());
+    for line in &lines[1..3] {
+        assert_eq!(line["line-width"], 20);
+        assert_eq!(line["jisage"]["level0"], 2);
+    }
+    assert!(lines[3].get("line-width").is_none());
+
+    Ok(())
+}
+
+#[test]
+fn test_consecutive_rubies_after_position_marker() -> Result<()> {
+    // ｜ は直後のルビにのみ掛かり、続くルビは通常どおり文字種で範囲を決める
+    let rendered = render_body("｜東京タワー《とうきょうタワー》大阪城《おおさかじょう》")?;
+    assert_eq!(
+        rendered["body"][0]["components"],
+        serde_json::json!([
+            {
+                "type": "ruby",
+                "ruby": [{ "type": "string", "value": "とうきょうタワー" }],
+                "children": [{ "type": "string", "value": "東京タワー" }],
+            },
+            {
+                "type": "ruby",
+                "ruby": [{ "type": "string", "value": "おおさかじょう" }],
+                "children": [{ "type": "string", "value": "大阪城" }],
+            },
+        ])
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_kome_without_annotation() -> Result<()> {
+    // ※［＃ でなければ外字注記として扱わない
+    assert_eq!(
+        tokenize_ruby_txt("※［注］")?,
+        vec![
+            RubyTxtToken::String("※［注".to_owned()),
+            RubyTxtToken::AnnotationEnd,
+        ]
+    );
+    assert_eq!(
+        tokenize_ruby_txt("※［＃「口＋世」、第3水準1-14-85］")?.first(),
+        Some(&RubyTxtToken::GaijiAnnotationStart)
+    );
+
+    // 対応しない '］' は文字として残る
+    let body = parse_body("※［注］")?;
+    assert!(matches!(
+        body.as_slice(),
+        [ParsedRubyTxtElement::String { value }] if value == "※［注］"
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_section_count() -> Result<()> {
+    let parse =
+        |body: &str| -> Result<_> { parse_ruby_txt(&tokenize_ruby_txt(&txt_of_body(body))?) };
+
+    let parsed = parse("一\r\n二")?;
+    assert!(!parsed.has_section_break());
+    assert_eq!(parsed.section_count(), 1);
+
+    let parsed = parse("一\r\n［＃改ページ］\r\n二\r\n［＃改見開き］\r\n三")?;
+    assert!(parsed.has_section_break());
+    assert_eq!(parsed.section_count(), 3);
+
+    Ok(())
+}
+
+#[test]
+fn test_drop_decorations() -> Result<()> {
+    let tokens = tokenize_ruby_txt(&txt_of_body(
+        "強い［＃「強い」に傍点］言葉と［＃太字］太い字［＃太字終わり］",
+    ))?;
+    let parsed = parse_ruby_txt(&tokens)?;
+
+    let rendered = serde_json::to_value(render_ruby_txt_with_options(
+        &parsed,
+        &RenderOptions {
+            drop_decorations: true,
+            ..Default::default()
+        },
+    )?)?;
+    assert_eq!(
+        rendered["body"][0]["components"],
+        serde_json::json!([{ "type": "string", "value": "強い言葉と太い字" }])
+    );
+
+    // 既定では残す
+    let rendered = serde_json::to_value(render_ruby_txt(&parsed)?)?;
+    assert_eq!(
+        rendered["body"][0]["components"].as_array().unwrap().len(),
+        6
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_kanji_number_in_jisage() -> Result<()> {
+    let body = parse_body("［＃ここから十二字下げ］\r\n本文\r\n［＃ここで字下げ終わり］")?;
+    assert!(matches!(
+        body.first(),
+        Some(ParsedRubyTxtElement::JisageStartAnnotation { level: 12 })
+    ));
+
+    let body = parse_body("本文［＃地から三字上げ］")?;
+    assert!(body
+        .iter()
+        .any(|el| matches!(el, ParsedRubyTxtElement::JiyoseAnnotation { level: 3 })));
+
+    Ok(())
+}
+
+// 字寄せの注記の後に何もない行
+#[test]
+fn test_empty_jiyose() -> Result<()> {
+    // 上田秋成（鵜月洋訳）「雨月物語」『現代語訳　雨月物語』
+    let rendered = render_body("［＃１字下げ］書肆［＃地から３字上げ］\r\n後")?;
+    let lines = rendered["body"].as_array().unwrap();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0]["jisage"]["level0"], 1);
+    assert_eq!(
+        lines[0]["components"],
+        serde_json::json!([{ "type": "string", "value": "書肆" }])
+    );
+    assert!(lines[0]["jiyose"].is_null());
+
+    Ok(())
+}
+
+#[test]
+fn test_ruby_pairs() -> Result<()> {
+    let tokens = tokenize_ruby_txt(&txt_of_body(
+        "漢字《かんじ》と｜東京タワー《とうきょうタワー》\r\n［＃地付き］漢字《かんじ》",
+    ))?;
+    let rendered = render_ruby_txt(&parse_ruby_txt(&tokens)?)?;
+
+    assert_eq!(
+        ruby_pairs(&rendered),
+        vec![
+            ("漢字".to_owned(), "かんじ".to_owned()),
+            ("東京タワー".to_owned(), "とうきょうタワー".to_owned()),
+            ("漢字".to_owned(), "かんじ".to_owned()),
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_teihon_in_body() -> Result<()> {
+    // 行頭の "底本：" でも、空行か区切りの直後でなければ末尾の始まりとしない
+    let tokens = tokenize_ruby_txt(&txt_of_body("本文\r\n底本：と書いてある。\r\n続き"))?;
+    let parsed = parse_ruby_txt(&tokens)?;
+
+    assert!(parsed.body.iter().any(
+        |el| matches!(el, ParsedRubyTxtElement::String { value } if value == "底本：と書いてある。")
+    ));
+    assert!(matches!(
+        parsed.footer.first(),
+        Some(ParsedRubyTxtElement::String { value }) if value == "底本：「底本」出版社"
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_footer_metadata() -> Result<()> {
+    let txt = fs::read_to_string("./tests/789_ruby_5639.ruby.txt")?;
+    let parsed = parse_ruby_txt(&tokenize_ruby_txt(&txt)?)?;
+
+    let metadata = parse_footer_metadata(&parsed);
+    assert_eq!(
+        metadata.original_book_title.as_deref(),
+        Some("夏目漱石全集1")
+    );
+    assert_eq!(metadata.inputter_name.as_deref(), Some("柴田卓治"));
+    assert!(metadata
+        .proofreader_name
+        .is_some_and(|name| name.starts_with("渡部峰子")));
+
+    Ok(())
+}
+
+#[test]
+fn test_line_to_plain_string() -> Result<()> {
+    let tokens = tokenize_ruby_txt(&txt_of_body(
+        "［＃２字下げ］漢字《かんじ》の［＃「の」に傍点］本",
+    ))?;
+    let rendered = render_ruby_txt(&parse_ruby_txt(&tokens)?)?;
+
+    let line = &rendered.body[0];
+    assert_eq!(line.to_plain_string(), "　　漢字（かんじ）の本");
+    assert_eq!(line.to_plain_string_no_ruby(), "　　漢字の本");
+
+    Ok(())
+}
+
+#[test]
+fn test_export_plain_text_source_notation() -> Result<()> {
+    // 文字としての 《 》 ｜ ［＃ は外字の注記で書かれる
+    let txt = txt_of_body(
+        "東京《とうきょう》の雪｜景色《けしき》\r\n※［＃始め二重山括弧、U+300A］題※［＃終わり二重山括弧、U+300B］※［＃縦線、U+FF5C］※［＃始め角括弧、U+FF3B］＃１］",
+    );
+    let rendered = render_ruby_txt(&parse_ruby_txt(&tokenize_ruby_txt(&txt)?)?)?;
+
+    let exported = export_plain_text(&rendered, RubyMode::SourceNotation);
+    assert_eq!(
+        exported,
+        "作品名\n著者名\n\n東京《とうきょう》の雪｜景色《けしき》\n※［＃始め二重山括弧、U+300A］題※［＃終わり二重山括弧、U+300B］※［＃縦線、U+FF5C］※［＃始め角括弧、U+FF3B］＃１］\n\n底本：「底本」出版社"
+    );
+    assert_eq!(
+        export_plain_text(&rendered, RubyMode::Parenthesized),
+        "作品名\n著者名\n\n東京（とうきょう）の雪景色（けしき）\n《題》｜［＃１］\n\n底本：「底本」出版社"
+    );
+
+    // 書き出したものを読み直すと元に戻る
+    let reparsed = render_ruby_txt(&parse_ruby_txt(&tokenize_ruby_txt(&exported)?)?)?;
+    assert_eq!(reparsed, rendered);
+
+    Ok(())
+}
+
+#[test]
+fn test_normalize_fullwidth() -> Result<()> {
+    let tokens = tokenize_ruby_txt(&txt_of_body(
+        "［＃２字下げ］ＡＢＣ！ｘ２［＃「２」は上付き小文字］＃",
+    ))?;
+    let options = ParseOptions {
+        normalize_fullwidth: true,
+        ..Default::default()
+    };
+    let parsed = parse_ruby_txt_with_options(&tokens, &options)?;
+
+    // 注記の中の字数はそのまま読める
+    assert!(matches!(
+        parsed.body.first(),
+        Some(ParsedRubyTxtElement::JisageAnnotation { level: 2 })
+    ));
+    assert_eq!(
+        parsed.body.iter().map(|e| e.text()).collect::<String>(),
+        "ABC!x2＃"
+    );
+
+    // 注記の対象も半角になるので本文と一致する
+    let rendered = serde_json::to_value(render_ruby_txt(&parsed)?)?;
+    assert_eq!(
+        rendered["body"][0]["components"][1]["children"][0]["value"],
+        "2"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_component_flatten() {
+    let string = |value: &str| RenderedRubyTxtComponent::String {
+        value: value.to_owned(),
+    };
+    let midashi = RenderedRubyTxtComponent::Midashi {
+        level: MidashiLevel::Naka,
+        style: MidashiStyle::Normal,
+        children: vec![
+            string("第一章　"),
+            RenderedRubyTxtComponent::Ruby {
+                ruby: vec![string("かんじ")],
+                children: vec![string("漢字")],
+            },
+        ],
+    };
+
+    assert_eq!(midashi.flatten().len(), 5);
+    assert_eq!(midashi.all_strings(), vec!["第一章　", "漢字", "かんじ"]);
+}
+
+#[test]
+fn test_merge_adjacent_strings() -> Result<()> {
+    let mut line: RenderedRubyTxtLine = serde_json::from_value(serde_json::json!({
+        "page-style": "continuous",
+        "jisage": { "level0": 0, "level1": 0 },
+        "components": [
+            { "type": "string", "value": "あ" },
+            { "type": "string", "value": "い" },
+            {
+                "type": "ruby",
+                "ruby": [{ "type": "string", "value": "か" }, { "type": "string", "value": "ん" }],
+                "children": [{ "type": "string", "value": "漢" }],
+            },
+            { "type": "string", "value": "う" },
+        ],
+        "jiyose": null,
+    }))?;
+    line.merge_adjacent_strings();
+
+    assert_eq!(
+        serde_json::to_value(&line)?["components"],
+        serde_json::json!([
+            { "type": "string", "value": "あい" },
+            {
+                "type": "ruby",
+                "ruby": [{ "type": "string", "value": "かん" }],
+                "children": [{ "type": "string", "value": "漢" }],
+            },
+            { "type": "string", "value": "う" },
+        ])
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_ruby_base_latin() -> Result<()> {
+    // アクセント記号付きの文字や単語中のアポストロフィも親文字に含める
+    for (body, base) in [
+        ("それはcafé《カフェ》だ", "café"),
+        ("それはcafe\u{301}《カフェ》だ", "cafe\u{301}"),
+        ("それはrock'n'roll《ロックンロール》だ", "rock'n'roll"),
+        ("それはself-made《セルフメイド》だ", "self-made"),
+    ] {
+        let rendered = render_body(body)?;
+        assert_eq!(
+            rendered["body"][0]["components"][1]["children"][0]["value"],
+            base
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_half_width_space_before_jisage() -> Result<()> {
+    // 半角の空白のみの行も空行として字下げできる
+    let rendered = render_body("  ［＃２字下げ］本文")?;
+    assert_eq!(
+        rendered["body"][0]["jisage"],
+        serde_json::json!({ "level0": 2, "level1": 2 })
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_pop_from_ruby_base() -> Result<()> {
+    // 注記の対象がルビの親文字の途中から始まるときは、読みを付け替えずにエラーにする
+    assert!(render_body("｜東京《とうきょう》［＃「京」は行右小書き］").is_err());
+
+    // 親文字全体ならルビごと取り出す
+    let rendered = render_body("都の｜東京《とうきょう》［＃「の東京」は行右小書き］")?;
+    assert_eq!(
+        rendered["body"][0]["components"],
+        serde_json::json!([
+            { "type": "string", "value": "都" },
+            {
+                "type": "kogaki",
+                "position": "line-right",
+                "children": [
+                    { "type": "string", "value": "の" },
+                    {
+                        "type": "ruby",
+                        "ruby": [{ "type": "string", "value": "とうきょう" }],
+                        "children": [{ "type": "string", "value": "東京" }],
+                    },
+                ],
+            },
+        ])
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_paragraphs() -> Result<()> {
+    let tokens = tokenize_ruby_txt(&txt_of_body(
+        "一行目\r\n二行目\r\n\r\n三行目\r\n［＃２字下げ］四行目\r\n［＃改ページ］\r\n五行目\r\n六行目",
+    ))?;
+    let parsed = parse_ruby_txt(&tokens)?;
+    let rendered = render_ruby_txt(&parsed)?;
+
+    let paragraphs: Vec<String> = rendered
+        .paragraphs()
+        .iter()
+        .map(|paragraph| paragraph.iter().flat_map(|c| c.all_strings()).collect())
+        .collect();
+    assert_eq!(
+        paragraphs,
+        vec!["一行目二行目", "三行目", "四行目", "五行目六行目"]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_jisage_with_orikaeshi() -> Result<()> {
+    // 各行がそれぞれ段落なので、どの行も 1 行目は level0、折り返しは level1 で字下げする
+    let rendered = render_body(
+        "［＃ここから３字下げ、折り返して５字下げ］\r\n一つ目の段落\r\n二つ目の段落\r\n［＃ここで字下げ終わり］",
+    )?;
+
+    let body = rendered["body"].as_array().unwrap();
+    assert_eq!(body.len(), 2);
+    for line in body {
+        assert_eq!(
+            line["jisage"],
+            serde_json::json!({ "level0": 3, "level1": 5 })
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_combined_page_directives() -> Result<()> {
+    assert_eq!(
+        parse_body("［＃改丁、ページの左右中央］")?,
+        vec![
+            ParsedRubyTxtElement::KaichoAttention,
+            ParsedRubyTxtElement::PageCenterAnnotation,
+        ]
+    );
+
+    // 注記を分けて書いたものと同じになる
+    for (combined, separated) in [
+        (
+            "前\r\n［＃改丁、ページの左右中央］\r\n本文",
+            "前\r\n［＃改丁］\r\n［＃ページの左右中央］\r\n本文",
+        ),
+        (
+            "前\r\n［＃改ページ、ページの左右中央］\r\n本文",
+            "前\r\n［＃改ページ］\r\n［＃ページの左右中央］\r\n本文",
+        ),
+    ] {
+        let rendered = render_body(combined)?;
+        assert_eq!(rendered, render_body(separated)?);
+        assert!(!rendered.to_string().contains("unknown-annotation"));
+    }
+
+    // 知らない指示を含むものはそのまま
+    assert!(matches!(
+        parse_body("［＃改丁、何か］")?.as_slice(),
+        [ParsedRubyTxtElement::UnknownAnnotation { .. }]
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_render_ruby_txt_combined() -> Result<()> {
+    let tokens = tokenize_ruby_txt(&txt_of_body("本文一\r\n本文二"))?;
+    let parsed = parse_ruby_txt(&tokens)?;
+    let rendered = render_ruby_txt(&parsed)?;
+    let combined = render_ruby_txt_combined(&parsed)?;
+
+    let rendered = serde_json::to_value(&rendered)?;
+    let combined = serde_json::to_value(&combined)?;
+
+    let header_len = rendered["header"].as_array().unwrap().len();
+    let body_len = rendered["body"].as_array().unwrap().len();
+    let footer_len = rendered["footer"].as_array().unwrap().len();
+    assert_eq!(
+        combined.as_array().unwrap().len(),
+        header_len + body_len + footer_len
+    );
+
+    // 各部の先頭は改ページになる
+    assert_eq!(combined[0], rendered["header"][0]);
+    assert_eq!(
+        combined[header_len]["page-style"],
+        serde_json::json!({ "kaipage": { "center": false } })
+    );
+    assert_eq!(
+        combined[header_len]["components"],
+        rendered["body"][0]["components"]
+    );
+    assert_eq!(
+        combined[header_len + body_len]["page-style"],
+        serde_json::json!({ "kaipage": { "center": false } })
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_accent_decomposition_with_ruby() -> Result<()> {
+    let expected = serde_json::json!([
+        { "type": "string", "value": "練習曲" },
+        {
+            "type": "ruby",
+            "ruby": [{ "type": "string", "value": "エチュード" }],
+            "children": [{ "type": "string", "value": "étude" }],
+        },
+    ]);
+
+    // アクセント分解の中のルビ
+    let rendered = render_body("練習曲〔e'tude《エチュード》〕")?;
+    assert_eq!(rendered["body"][0]["components"], expected);
+
+    // アクセント分解の後のルビ
+    let rendered = render_body("練習曲〔e'tude〕《エチュード》")?;
+    assert_eq!(rendered["body"][0]["components"], expected);
+
+    Ok(())
+}
+
+#[test]
+fn test_accent_decomposition_partial() -> Result<()> {
+    let string = |value: &str| {
+        vec![ParsedRubyTxtElement::String {
+            value: value.to_owned(),
+        }]
+    };
+
+    // すべて合成される
+    assert_eq!(parse_body("〔e'a`〕")?, string("éà"));
+
+    // 一部のみ合成される場合も、残りを含めて領域全体をアクセント分解とする
+    assert_eq!(parse_body("〔a'bc〕")?, string("ábc"));
+    assert_eq!(parse_body("〔bca'〕")?, string("bcá"));
+
+    // 何も合成されなければアクセント分解ではない
+    assert_eq!(parse_body("〔abc〕")?, string("〔abc〕"));
+
+    Ok(())
+}
+
+#[test]
+fn test_keep_source() -> Result<()> {
+    let tokens = tokenize_ruby_txt(&txt_of_body(
+        "［＃２字下げ］本文［＃「本文」に傍点］［＃「本文」はママ］",
+    ))?;
+    let options = ParseOptions {
+        keep_source: true,
+        ..Default::default()
+    };
+    let parsed = parse_ruby_txt_with_options(&tokens, &options)?;
+
+    // 無視される注記には付かない
+    let sources: Vec<_> = parsed
+        .body
+        .iter()
+        .filter_map(|e| match e {
+            ParsedRubyTxtElement::AnnotationSource { raw } => Some(raw.as_str()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(sources, vec!["［＃２字下げ］", "［＃「本文」に傍点］"]);
+    assert!(matches!(
+        parsed.body.get(1),
+        Some(ParsedRubyTxtElement::JisageAnnotation { level: 2 })
+    ));
+
+    // 描画の結果は変わらない
+    assert_eq!(
+        serde_json::to_value(render_ruby_txt(&parsed)?)?,
+        serde_json::to_value(render_ruby_txt(&parse_ruby_txt(&tokens)?)?)?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_jisage_with_orikaeshi_mixed_numbers() -> Result<()> {
+    for (annotation, expected) in [
+        ("ここから3字下げ、折り返して五字下げ", (3, 5)),
+        ("ここから三字下げ、折り返して５字下げ", (3, 5)),
+        ("ここから１字下げ、折り返して12字下げ", (1, 12)),
+    ] {
+        let body = parse_body(&format!(
+            "［＃{}］\r\n本文\r\n［＃ここで字下げ終わり］",
+            annotation
+        ))?;
+        assert_eq!(
+            body.first(),
+            Some(&ParsedRubyTxtElement::JisageWithOrikaeshiStartAnnotation {
+                level0: expected.0,
+                level1: expected.1,
+            }),
+            "{}",
+            annotation
+        );
+    }
+
+    Ok(())
+}
+
+// 数として読めないものは作品ごと失敗させず、不明な注記とする
+#[test]
+fn test_malformed_number_in_annotation() -> Result<()> {
+    for raw in [
+        "十十字下げ",
+        "ここから十十字下げ",
+        "ここから十十字詰め",
+        "ここから十十字下げ、折り返して２字下げ",
+        "ここから改行天付き、折り返して十十字下げ",
+        "地から十十字上げ",
+        "ここから地から十十字上げ",
+        "ここから罫囲み、十十字下げ",
+    ] {
+        let body = parse_body(&format!("［＃{}］本文", raw))?;
+        assert_eq!(
+            body.first(),
+            Some(&ParsedRubyTxtElement::UnknownAnnotation {
+                raw: raw.to_owned(),
+                args: vec![ParsedRubyTxtElement::String {
+                    value: raw.to_owned()
+                }],
+            }),
+            "{}",
+            raw
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_keigakomi_with_jisage() -> Result<()> {
+    assert_eq!(
+        parse_body("［＃ここから罫囲み、２字下げ］")?,
+        vec![
+            ParsedRubyTxtElement::KeigakomiStart,
+            ParsedRubyTxtElement::JisageStartAnnotation { level: 2 },
+        ]
+    );
+    assert_eq!(
+        parse_body("［＃ここで字下げ、罫囲み終わり］")?,
+        vec![
+            ParsedRubyTxtElement::JisageEndAnnotation,
+            ParsedRubyTxtElement::KeigakomiEnd,
+        ]
+    );
+
+    let layouts = |body: &str| -> Result<Vec<(serde_json::Value, bool)>> {
+        let rendered = render_body(body)?;
+        Ok(rendered["body"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|line| {
+                (
+                    line["jisage"]["level0"].clone(),
+                    line.get("keigakomi").is_some_and(|k| k == true),
+                )
+            })
+            .collect())
+    };
+
+    // まとめて閉じる
+    assert_eq!(
+        layouts(
+            "前\r\n［＃ここから罫囲み、２字下げ］\r\n中\r\n［＃ここで字下げ、罫囲み終わり］\r\n後"
+        )?,
+        vec![
+            (serde_json::json!(0), false),
+            (serde_json::json!(2), true),
+            (serde_json::json!(0), false),
+        ]
+    );
+
+    // 別々に閉じる
+    assert_eq!(
+        layouts(
+            "［＃ここから罫囲み、２字下げ］\r\n一\r\n［＃ここで字下げ終わり］\r\n二\r\n［＃ここで罫囲み終わり］\r\n三"
+        )?,
+        vec![
+            (serde_json::json!(2), true),
+            (serde_json::json!(0), true),
+            (serde_json::json!(0), false),
+        ]
+    );
+
+    Ok(())
+}
+
+// UnknownAnnotation の raw は元の ［＃…］ の中身そのもの
+#[test]
+fn test_unknown_annotation_raw() -> Result<()> {
+    for annotation in [
+        "［＃謎の注記］",
+        "［＃「本文」は謎］",
+        "［＃謎の※［＃「てへん＋劣」、U+6318、135-9］注記］",
+        "［＃謎の／＼注記］",
+    ] {
+        let parsed = parse_body(&format!("本文{}", annotation))?;
+        let Some(ParsedRubyTxtElement::UnknownAnnotation { raw, .. }) = parsed.last() else {
+            panic!("{}: {:?}", annotation, parsed);
+        };
+        assert_eq!(format!("［＃{}］", raw), annotation);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_stray_hash_in_annotation() -> Result<()> {
+    // ［ の後でない ＃ はただの文字なので入れ子の数え方に影響しない
+    for (body, raw) in [("本文［＃＃謎］", "＃謎"), ("本文［＃＃］", "＃")] {
+        let parsed = parse_body(body)?;
+        assert_eq!(
+            parsed.last(),
+            Some(&ParsedRubyTxtElement::UnknownAnnotation {
+                raw: raw.to_owned(),
+                args: vec![ParsedRubyTxtElement::String {
+                    value: raw.to_owned()
+                }],
+            }),
+            "{}",
+            body
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_header_metadata() -> Result<()> {
+    let metadata_of = |txt: &str| -> Result<HeaderMeta> {
+        Ok(extract_header_meta(&parse_ruby_txt(&tokenize_ruby_txt(
+            txt,
+        )?)?))
+    };
+    let metadata =
+        |title: &str, subtitle: Option<&str>, author: Option<&str>, translator: Option<&str>| {
+            HeaderMeta {
+                title: Some(title.to_owned()),
+                subtitle: subtitle.map(|s| s.to_owned()),
+                author: author.map(|s| s.to_owned()),
+                translator: translator.map(|s| s.to_owned()),
+            }
+        };
+
+    let txt = fs::read_to_string("./tests/789_ruby_5639.ruby.txt")?;
+    assert_eq!(
+        metadata_of(&txt)?,
+        metadata("吾輩は猫である", None, Some("夏目漱石"), None)
+    );
+
+    let footer = "\r\n\r\n底本：「底本」出版社\r\n";
+    assert_eq!(
+        metadata_of(&format!("作品名\r\n副題\r\n著者名\r\n\r\n本文{}", footer))?,
+        metadata("作品名", Some("副題"), Some("著者名"), None)
+    );
+    assert_eq!(
+        metadata_of(&format!(
+            "作品名\r\n著者名\r\n訳者名訳\r\n\r\n本文{}",
+            footer
+        ))?,
+        metadata("作品名", None, Some("著者名"), Some("訳者名訳"))
+    );
+    assert_eq!(
+        metadata_of(&format!(
+            "作品名\r\n副題\r\n著者名\r\n訳者名訳\r\n\r\n本文{}",
+            footer
+        ))?,
+        metadata("作品名", Some("副題"), Some("著者名"), Some("訳者名訳"))
+    );
+
+    // 古い形式
+    assert_eq!(
+        metadata_of(&format!("作品名\r\n\r\n著者名\r\n\r\n本文。{}", footer))?,
+        metadata("作品名", None, Some("著者名"), None)
+    );
+    assert_eq!(
+        metadata_of(&format!("作品名\r\n\r\n本文。\r\n続き。{}", footer))?,
+        metadata("作品名", None, None, None)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_gaiji_unicode_without_area_code() -> Result<()> {
+    for body in [
+        "※［＃ハングル、U+AC00、1-2］",
+        "※［＃ハングル、U+AC00］",
+        "※［＃ハングル、U+AC00、ガと読む］",
+    ] {
+        assert_eq!(
+            parse_body(body)?,
+            vec![ParsedRubyTxtElement::String {
+                value: "가".to_owned()
+            }],
+            "{}",
+            body
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_gaiji_kana_with_mark() -> Result<()> {
+    for (body, expected) in [
+        // 鼻濁音などの表記に使われる、合成済みの文字がないもの
+        ("※［＃半濁点付き平仮名か、1-4-87］", "か\u{309A}"),
+        ("※［＃半濁点付き片仮名セ、1-5-90］", "セ\u{309A}"),
+        ("※［＃濁点付き片仮名イ］", "イ\u{3099}"),
+        // 合成済みの文字があるもの
+        ("※［＃濁点付き片仮名ワ、1-7-82］", "ヷ"),
+        ("※［＃半濁点付き平仮名は］", "ぱ"),
+    ] {
+        assert_eq!(
+            parse_body(body)?,
+            vec![ParsedRubyTxtElement::String {
+                value: expected.to_owned()
+            }],
+            "{}",
+            body
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_empty_annotation() -> Result<()> {
+    let body = parse_body("本文［＃］：入力者注")?;
+    assert_eq!(
+        body,
+        vec![
+            ParsedRubyTxtElement::String {
+                value: "本文".to_owned()
+            },
+            ParsedRubyTxtElement::EmptyAnnotation,
+            ParsedRubyTxtElement::String {
+                value: "：入力者注".to_owned()
+            },
+        ]
+    );
+    assert_eq!(
+        body.iter().map(|e| e.text()).collect::<String>(),
+        "本文：入力者注"
+    );
+
+    let tokens = tokenize_ruby_txt(&txt_of_body("本文［＃］：入力者注"))?;
+    let rendered = render_ruby_txt(&parse_ruby_txt(&tokens)?)?;
+    assert_eq!(rendered.body[0].to_plain_string(), "本文：入力者注");
+    assert_eq!(
+        rendered.body[0].components()[1],
+        RenderedRubyTxtComponent::EmptyAnnotation
+    );
+
+    // 青空文庫の書式では ［＃］ を書き戻す
+    let exported = export_plain_text(&rendered, RubyMode::SourceNotation);
+    assert!(exported.contains("\n本文［＃］：入力者注\n"));
+    let reparsed = render_ruby_txt(&parse_ruby_txt(&tokenize_ruby_txt(&exported)?)?)?;
+    assert_eq!(reparsed, rendered);
+
+    Ok(())
+}
+
+#[test]
+fn test_strict_empty_ruby_reading() -> Result<()> {
+    // 読みが ［＃］ のみで、文字列としては空になる
+    let tokens = tokenize_ruby_txt(&txt_of_body("一行目\r\n漢字《［＃］》"))?;
+
+    assert!(parse_ruby_txt(&tokens).is_ok());
+
+    let options = ParseOptions {
+        strict: true,
+        ..Default::default()
+    };
+    let error = parse_ruby_txt_with_options(&tokens, &options).unwrap_err();
+    assert!(error.to_string().contains("line 2 of body"), "{}", error);
+
+    let tokens = tokenize_ruby_txt(&txt_of_body("漢字《かんじ》"))?;
+    assert!(parse_ruby_txt_with_options(&tokens, &options).is_ok());
+
+    Ok(())
+}
+
+#[test]
+fn test_figure_with_caption() -> Result<()> {
+    // キャプションが図を囲む
+    let rendered = render_body(
+        "［＃キャプション］［＃挿絵（fig1_1.png）入る］挿絵の説明［＃キャプション終わり］",
+    )?;
+    assert_eq!(
+        rendered["body"][0]["components"],
+        serde_json::json!([{
+            "type": "figure",
+            "image": { "path": "fig1_1.png", "alt": "挿絵" },
+            "caption": [{ "type": "string", "value": "挿絵の説明" }],
+        }])
+    );
+
+    // 図の直後の行のキャプション
+    let rendered = render_body(
+        "［＃挿絵（fig1_1.png）入る］\r\n［＃キャプション］挿絵の説明［＃キャプション終わり］\r\n本文",
+    )?;
+    assert_eq!(
+        rendered["body"][0]["components"][0]["type"],
+        serde_json::json!("figure")
+    );
+    assert_eq!(
+        rendered["body"][1]["components"],
+        serde_json::json!([{ "type": "string", "value": "本文" }])
+    );
+
+    // 図のないキャプション
+    let rendered = render_body("本文\r\n［＃キャプション］説明［＃キャプション終わり］")?;
+    assert_eq!(
+        rendered["body"][1]["components"],
+        serde_json::json!([{
+            "type": "caption",
+            "children": [{ "type": "string", "value": "説明" }],
+        }])
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_ruby_base_with_old_kana() -> Result<()> {
+    let render = |old_kana: bool| -> Result<serde_json::Value> {
+        let tokens = tokenize_ruby_txt(&txt_of_body("人々は屡〻《しばしば》訪れた"))?;
+        let parsed = parse_ruby_txt(&tokens)?;
+        let options = RenderOptions {
+            old_kana,
+            ..Default::default()
+        };
+        Ok(serde_json::to_value(render_ruby_txt_with_options(
+            &parsed, &options,
+        )?)?)
+    };
+
+    // 文字種のみで判定すると 〻 だけに掛かる
+    assert_eq!(
+        render(false)?["body"][0]["components"],
+        serde_json::json!([
+            { "type": "string", "value": "人々は屡" },
+            {
+                "type": "ruby",
+                "ruby": [{ "type": "string", "value": "しばしば" }],
+                "children": [{ "type": "string", "value": "〻" }],
+            },
+            { "type": "string", "value": "訪れた" },
+        ])
+    );
+
+    // 旧仮名遣いでは 〻 を直前の漢字に続ける
+    assert_eq!(
+        render(true)?["body"][0]["components"],
+        serde_json::json!([
+            { "type": "string", "value": "人々は" },
+            {
+                "type": "ruby",
+                "ruby": [{ "type": "string", "value": "しばしば" }],
+                "children": [{ "type": "string", "value": "屡〻" }],
+            },
+            { "type": "string", "value": "訪れた" },
+        ])
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_skipped_symbol_description_block() -> Result<()> {
+    let separator = "-------------------------------------------------------";
+    let description = format!(
+        "{}\r\n【テキスト中に現れる記号について】\r\n\r\n《》：ルビ\r\n（例）漢字《かんじ》\r\n{}",
+        separator, separator
+    );
+
+    // 冒頭の記号説明
+    let body = parse_body(&format!("{}\r\n\r\n本文", description))?;
+    assert_eq!(
+        body,
+        vec![ParsedRubyTxtElement::String {
+            value: "本文".to_owned()
+        }]
+    );
+
+    // 途中の記号説明の前後で改ページが重ならない
+    let body = parse_body(&format!("本文\r\n{}\r\n続き", description))?;
+    assert_eq!(
+        body,
+        vec![
+            ParsedRubyTxtElement::String {
+                value: "本文".to_owned()
+            },
+            ParsedRubyTxtElement::NewLine,
+            ParsedRubyTxtElement::KaipageAttention,
+            ParsedRubyTxtElement::NewLine,
+            ParsedRubyTxtElement::String {
+                value: "続き".to_owned()
+            },
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_rendered_line_accessors() -> Result<()> {
+    let tokens = tokenize_ruby_txt(&txt_of_body(
+        "［＃ここから２字下げ、折り返して３字下げ］\r\n本文［＃地から１字上げ］署名\r\n［＃ここで字下げ終わり］\r\n［＃改ページ］\r\n次",
+    ))?;
+    let rendered = render_ruby_txt(&parse_ruby_txt(&tokens)?)?;
+
+    assert_eq!(
+        rendered.body[1].page_style(),
+        &PageStyle::Kaipage { center: false }
+    );
+
+    let line = &rendered.body[0];
+    assert_eq!(line.page_style(), &PageStyle::Continuous);
+    assert_eq!((line.jisage().level0(), line.jisage().level1()), (2, 3));
+    assert_eq!(
+        line.components(),
+        &[RenderedRubyTxtComponent::String {
+            value: "本文".to_owned()
+        }]
+    );
+
+    let jiyose = line.jiyose().context("No jiyose")?;
+    assert_eq!(jiyose.level(), 1);
+    assert_eq!(
+        jiyose.lines(),
+        &[vec![RenderedRubyTxtComponent::String {
+            value: "署名".to_owned()
+        }]]
+    );
+    assert_eq!(line.line_width(), None);
+    assert!(!line.keigakomi());
+
+    Ok(())
+}
+
+#[test]
+fn test_construct_rendered_layout() -> Result<()> {
+    let tokens = tokenize_ruby_txt(&txt_of_body(
+        "［＃ここから２字下げ、折り返して３字下げ］\r\n本文［＃地から１字上げ］署名\r\n［＃ここで字下げ終わり］",
+    ))?;
+    let rendered = render_ruby_txt(&parse_ruby_txt(&tokens)?)?;
+
+    let line = &rendered.body[0];
+    assert_eq!(line.jisage(), &Jisage::new(2, 3));
+    assert_eq!(
+        line.jiyose(),
+        Some(&Jiyose::new(
+            1,
+            vec![vec![RenderedRubyTxtComponent::String {
+                value: "署名".to_owned()
+            }]]
+        ))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_block_annotation_closed_at_block_boundary() -> Result<()> {
+    let separator = "-------------------------------------------------------";
+    let txt = txt_of_body(&format!(
+        "［＃ここから２字下げ］\r\n本文\r\n［＃ここから地付き］\r\n署名\r\n{}\r\n次",
+        separator
+    ));
+    let tokens = tokenize_ruby_txt(&txt)?;
+
+    let warnings = Arc::new(Mutex::new(Vec::new()));
+    let options = ParseOptions {
+        on_warning: Some(Arc::new({
+            let warnings = warnings.clone();
+            move |warning: &ParseWarning| warnings.lock().unwrap().push(warning.to_string())
+        })),
+        ..Default::default()
+    };
+    let parsed = parse_ruby_txt_with_options(&tokens, &options)?;
+    // 開いた順と逆に閉じる
+    assert_eq!(warnings.lock().unwrap().len(), 2);
+    assert!(warnings.lock().unwrap()[0].contains("JitsukiEndAnnotation"));
+    assert!(warnings.lock().unwrap()[1].contains("JisageEndAnnotation"));
+
+    let rendered = render_ruby_txt(&parsed)?;
+    // 地付きのブロックは直前の行に入る
+    assert_eq!(rendered.body[0].jisage(), &Jisage::new(2, 2));
+    assert_eq!(rendered.body[0].jiyose().map(|j| j.level()), Some(0));
+
+    // 次のブロックに字下げが持ち越されない
+    let next = &rendered.body[1];
+    assert_eq!(next.page_style(), &PageStyle::Kaipage { center: false });
+    assert_eq!(next.jisage(), &Jisage::new(0, 0));
+    assert_eq!(
+        next.components(),
+        &[RenderedRubyTxtComponent::String {
+            value: "次".to_owned()
+        }]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_mojigumi() -> Result<()> {
+    let tokens = tokenize_ruby_txt(&txt_of_body(
+        "［＃文字組み：行頭の約物の扱い：半角］本文\r\n［＃文字組み：謎の設定］本文",
+    ))?;
+
+    let warnings = Arc::new(Mutex::new(Vec::new()));
+    let options = ParseOptions {
+        on_warning: Some(Arc::new({
+            let warnings = warnings.clone();
+            move |warning: &ParseWarning| warnings.lock().unwrap().push(warning.clone())
+        })),
+        ..Default::default()
+    };
+    let parsed = parse_ruby_txt_with_options(&tokens, &options)?;
+    // 未知の設定は知らせるが、そのまま残す
+    assert_eq!(
+        *warnings.lock().unwrap(),
+        vec![ParseWarning::UnknownLayoutHint {
+            kind: "mojigumi".to_owned(),
+            value: "謎の設定".to_owned(),
+        }]
+    );
+    assert_eq!(
+        warnings.lock().unwrap()[0].to_string(),
+        r#"Unknown mojigumi style: "謎の設定""#
+    );
+
+    let rendered = render_ruby_txt(&parsed)?;
+    let hints: Vec<_> = rendered
+        .body
+        .iter()
+        .map(|line| line.components()[0].clone())
+        .collect();
+    assert_eq!(
+        hints,
+        vec![
+            RenderedRubyTxtComponent::LayoutHint {
+                kind: "mojigumi".to_owned(),
+                value: "行頭の約物の扱い：半角".to_owned(),
+            },
+            RenderedRubyTxtComponent::LayoutHint {
+                kind: "mojigumi".to_owned(),
+                value: "謎の設定".to_owned(),
+            },
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_track_lines() -> Result<()> {
+    let options = ParseOptions {
+        track_lines: true,
+        ..Default::default()
+    };
+    let txt = txt_of_body(
+        "一行目\r\n\r\n［＃ここから２字下げ］\r\n字下げ\r\n［＃ここで字下げ終わり］\r\n漢字《かんじ》",
+    );
+    let rendered = render_ruby_txt(&parse_ruby_txt_with_options(
+        &tokenize_ruby_txt(&txt)?,
+        &options,
+    )?)?;
+
+    let source_lines = |lines: &[RenderedRubyTxtLine]| -> Vec<_> {
+        lines.iter().map(|line| line.source_line()).collect()
+    };
+    assert_eq!(source_lines(&rendered.header), vec![Some(1), Some(2)]);
+    assert_eq!(
+        source_lines(&rendered.body),
+        vec![Some(4), Some(5), Some(7), Some(9)]
+    );
+    assert_eq!(source_lines(&rendered.footer), vec![Some(11)]);
+
+    // 指定しなければ付けない
+    let rendered = render_body("一行目")?;
+    assert!(rendered["body"][0].get("source-line").is_none());
+
+    // 行番号の他はレンダリングの結果が変わらない
+    let paths = fs::read_dir("./tests")?.chain(fs::read_dir("./tests/fixtures")?);
+    for path in paths {
+        let path = path.unwrap().path();
+        let file_name = path.file_name().unwrap().to_str().unwrap();
+        if !file_name.ends_with(RUBY_TXT_SUFFIX) {
+            continue;
+        }
+
+        let txt = fs::read_to_string(&path).unwrap();
+        let tokens = tokenize_ruby_txt(&txt)?;
+        let expected = render_ruby_txt(&parse_ruby_txt(&tokens)?)?;
+        let actual = render_ruby_txt(&parse_ruby_txt_with_options(&tokens, &options)?)
+            .with_context(|| format!("Failed to render {} with line numbers", file_name))?;
+
+        let line_count = txt.lines().count();
+        for (expected, actual) in [
+            (&expected.header, &actual.header),
+            (&expected.body, &actual.body),
+            (&expected.footer, &actual.footer),
+        ] {
+            assert_eq!(expected.len(), actual.len(), "{}", file_name);
+            for (expected, actual) in expected.iter().zip(actual) {
+                assert_eq!(expected.components(), actual.components(), "{}", file_name);
+                assert_eq!(expected.jisage(), actual.jisage(), "{}", file_name);
+                assert_eq!(expected.jiyose(), actual.jiyose(), "{}", file_name);
+                let source_line = actual.source_line().context("No source line")?;
+                assert!(source_line <= line_count, "{}", file_name);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_ruby_base_with_kanji_like_marks() -> Result<()> {
+    // 々〆〇ヶ仝 は漢字として親文字に含める
+    for (body, prefix, base, ruby) in [
+        ("道行く人々《ひとびと》", "道行く", "人々", "ひとびと"),
+        ("正月の書〆《かきじめ》", "正月の", "書〆", "かきじめ"),
+        ("あと一ヶ月《いっかげつ》", "あと", "一ヶ月", "いっかげつ"),
+        (
+            "今年は二〇〇〇年《にせんねん》",
+            "今年は",
+            "二〇〇〇年",
+            "にせんねん",
+        ),
+        ("同じく仝《どう》", "同じく", "仝", "どう"),
+    ] {
+        let rendered = render_body(body)?;
+        assert_eq!(
+            rendered["body"][0]["components"],
+            serde_json::json!([
+                { "type": "string", "value": prefix },
+                {
+                    "type": "ruby",
+                    "ruby": [{ "type": "string", "value": ruby }],
+                    "children": [{ "type": "string", "value": base }],
+                },
+            ]),
+            "{}",
+            body
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_parse_header_only() -> Result<()> {
+    let tokens = tokenize_ruby_txt(&txt_of_body("本文"))?;
+    assert_eq!(parse_header_only(&tokens)?, parse_ruby_txt(&tokens)?.header);
+
+    // 本文・末尾は読まない
+    let tokens = tokenize_ruby_txt("作品名\r\n著者名\r\n\r\n末尾のない本文［＃")?;
+    assert!(parse_ruby_txt(&tokens).is_err());
+    assert_eq!(
+        parse_header_only(&tokens)?,
+        vec![
+            ParsedRubyTxtElement::String {
+                value: "作品名".to_owned()
+            },
+            ParsedRubyTxtElement::NewLine,
+            ParsedRubyTxtElement::String {
+                value: "著者名".to_owned()
+            },
+        ]
+    );
+
+    assert!(parse_header_only(&[]).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_diff_parsed_body() -> Result<()> {
+    let parse = |body: &str| -> Result<ParsedRubyTxt> {
+        parse_ruby_txt(&tokenize_ruby_txt(&txt_of_body(body))?)
+    };
+    let string = |value: &str| ParsedRubyTxtElement::String {
+        value: value.to_owned(),
+    };
+    let new_line = ParsedRubyTxtElement::NewLine;
+
+    // 同じ
+    assert_eq!(
+        diff_parsed_body(&parse("一\r\n二")?, &parse("一\r\n二")?),
+        vec![
+            DiffItem::Same(string("一")),
+            DiffItem::Same(new_line.clone()),
+            DiffItem::Same(string("二")),
+        ]
+    );
+
+    // 追加のみ
+    assert_eq!(
+        diff_parsed_body(&parse("一\r\n二")?, &parse("一\r\n追加\r\n二")?),
+        vec![
+            DiffItem::Same(string("一")),
+            DiffItem::Same(new_line.clone()),
+            DiffItem::OnlyInB(string("追加")),
+            DiffItem::OnlyInB(new_line.clone()),
+            DiffItem::Same(string("二")),
+        ]
+    );
+
+    // 削除のみ
+    assert_eq!(
+        diff_parsed_body(&parse("一\r\n削除\r\n二")?, &parse("一\r\n二")?),
+        vec![
+            DiffItem::Same(string("一")),
+            DiffItem::Same(new_line.clone()),
+            DiffItem::OnlyInA(string("削除")),
+            DiffItem::OnlyInA(new_line.clone()),
+            DiffItem::Same(string("二")),
+        ]
+    );
+
+    // 置き換えと追加
+    assert_eq!(
+        diff_parsed_body(&parse("一\r\n二\r\n三")?, &parse("一\r\n弐\r\n三\r\n四")?),
+        vec![
+            DiffItem::Same(string("一")),
+            DiffItem::Same(new_line.clone()),
+            DiffItem::OnlyInA(string("二")),
+            DiffItem::OnlyInB(string("弐")),
+            DiffItem::Same(new_line.clone()),
+            DiffItem::Same(string("三")),
+            DiffItem::OnlyInB(new_line.clone()),
+            DiffItem::OnlyInB(string("四")),
+        ]
+    );
+
+    // 長い本文でも差分が小さければ速い
+    let txt = fs::read_to_string("./tests/789_ruby_5639.ruby.txt")?;
+    let a = parse_ruby_txt(&tokenize_ruby_txt(&txt)?)?;
+    let mut b = parse_ruby_txt(&tokenize_ruby_txt(&txt)?)?;
+    b.body.remove(b.body.len() / 2);
+    b.body.remove(b.body.len() / 3);
+    b.body.insert(10, string("追加"));
+
+    let diff = diff_parsed_body(&a, &b);
+    let only_in_a = diff
+        .iter()
+        .filter(|item| matches!(item, DiffItem::OnlyInA(_)))
+        .count();
+    let only_in_b = diff
+        .iter()
+        .filter(|item| matches!(item, DiffItem::OnlyInB(_)))
+        .count();
+    assert!(only_in_a <= 2 && only_in_b <= 1 && only_in_a + only_in_b >= 1);
+
+    // 差分から両方を復元できる
+    let restore = |keep_a: bool| -> Vec<ParsedRubyTxtElement> {
+        diff.iter()
+            .filter_map(|item| match item {
+                DiffItem::Same(el) => Some(el.clone()),
+                DiffItem::OnlyInA(el) if keep_a => Some(el.clone()),
+                DiffItem::OnlyInB(el) if !keep_a => Some(el.clone()),
+                _ => None,
+            })
+            .collect()
+    };
+    assert_eq!(restore(true), a.body);
+    assert_eq!(restore(false), b.body);
+
+    Ok(())
+}
+
+// 範囲の要素の文字列 (「○○」に… の注記は対象の文字列)
+fn span_text(body: &[ParsedRubyTxtElement], start_index: usize, end_index: usize) -> String {
+    body[start_index..end_index]
+        .iter()
+        .map(|el| match el {
+            ParsedRubyTxtElement::StringDecoration { target, .. }
+            | ParsedRubyTxtElement::BouDecoration { target, .. } => {
+                target.iter().map(|t| t.text()).collect()
+            }
+            el => el.text(),
+        })
+        .collect()
+}
+
+#[test]
+fn test_all_string_decoration_spans() -> Result<()> {
+    let tokens = tokenize_ruby_txt(&txt_of_body(
+        "［＃太字］外［＃斜体］内［＃斜体終わり］外［＃太字終わり］\r\n強い［＃「強い」は太字］［＃斜体］斜め［＃斜体終わり］\r\n［＃太字］行を\r\n越える［＃太字終わり］［＃斜体終わり］",
+    ))?;
+    let parsed = parse_ruby_txt(&tokens)?;
+
+    let spans: Vec<_> = all_string_decoration_spans(&parsed)
+        .into_iter()
+        .map(|span| {
+            (
+                span_text(&parsed.body, span.start_index, span.end_index),
+                span.style,
+            )
+        })
+        .collect();
+    assert_eq!(
+        spans,
+        vec![
+            // 入れ子
+            ("外内外".to_owned(), StringDecorationStyle::Bold),
+            ("内".to_owned(), StringDecorationStyle::Italic),
+            // 隣り合う
+            ("強い".to_owned(), StringDecorationStyle::Bold),
+            ("斜め".to_owned(), StringDecorationStyle::Italic),
+            // 行をまたぐ
+            ("行を\n越える".to_owned(), StringDecorationStyle::Bold),
+            // 対応の取れない終わりは無視する
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_all_bou_decoration_spans() -> Result<()> {
+    let styles = [
+        ("傍点", BouDecorationStyle::SesameDotBouten),
+        ("白ゴマ傍点", BouDecorationStyle::WhiteSesameDotBouten),
+        ("丸傍点", BouDecorationStyle::BlackCircleBouten),
+        ("白丸傍点", BouDecorationStyle::WhiteCircleBouten),
+        (
+            "黒三角傍点",
+            BouDecorationStyle::BlackUpPointingTriangleBouten,
+        ),
+        (
+            "白三角傍点",
+            BouDecorationStyle::WhiteUpPointingTriangleBouten,
+        ),
+        ("二重丸傍点", BouDecorationStyle::BullseyeBouten),
+        ("蛇の目傍点", BouDecorationStyle::FisheyeBouten),
+        ("ばつ傍点", BouDecorationStyle::SaltireBouten),
+        ("傍線", BouDecorationStyle::SolidBousen),
+        ("二重傍線", BouDecorationStyle::DoubleBousen),
+        ("鎖線", BouDecorationStyle::DottedBousen),
+        ("破線", BouDecorationStyle::DashedBousen),
+        ("波線", BouDecorationStyle::WaveBousen),
+    ];
+    for (name, style) in styles {
+        for (side_prefix, side) in [
+            ("", BouDecorationSide::Right),
+            ("左", BouDecorationSide::Left),
+        ] {
+            // ［＃「○○」に…］ と ［＃…］…［＃…終わり］
+            let target_side = if side_prefix.is_empty() { "" } else { "の左" };
+            let range_side = if side_prefix.is_empty() { "" } else { "左に" };
+            let tokens = tokenize_ruby_txt(&txt_of_body(&format!(
+                "対象［＃「対象」{}に{}］と［＃{}{}］範囲［＃{}{}終わり］",
+                target_side, name, range_side, name, range_side, name
+            )))?;
+            let parsed = parse_ruby_txt(&tokens)?;
+
+            let spans: Vec<_> = all_bou_decoration_spans(&parsed)
+                .into_iter()
+                .map(|span| {
+                    (
+                        span_text(&parsed.body, span.start_index, span.end_index),
+                        span.style,
+                        span.side,
+                    )
+                })
+                .collect();
+            assert_eq!(
+                spans,
+                vec![
+                    ("対象".to_owned(), style.clone(), side.clone()),
+                    ("範囲".to_owned(), style.clone(), side.clone()),
+                ],
+                "{}{}",
+                side_prefix,
+                name
+            );
+        }
+    }
+
+    // 向きの違う終わりとは対応させない
+    let tokens = tokenize_ruby_txt(&txt_of_body(
+        "［＃傍点］点［＃左に傍点終わり］［＃傍点終わり］",
+    ))?;
+    let parsed = parse_ruby_txt(&tokens)?;
+    let spans = all_bou_decoration_spans(&parsed);
+    assert_eq!(spans.len(), 1);
+    assert_eq!(
+        span_text(&parsed.body, spans[0].start_index, spans[0].end_index),
+        "点"
+    );
+
+    Ok(())
+}
@@ -0,0 +1,85 @@
+use aozorabunko_json::utility::str::{decode_shift_jis, parse_number_kanji, CharType};
+
+#[test]
+fn test_char_type_runs() {
+    assert_eq!(CharType::runs(""), vec![]);
+
+    let s = "今日はgood天気";
+    let runs = CharType::runs(s);
+    assert_eq!(
+        runs.iter()
+            .map(|(t, r)| (*t, &s[r.clone()]))
+            .collect::<Vec<_>>(),
+        vec![
+            (CharType::Kanji, "今日"),
+            (CharType::Hiragana, "は"),
+            (CharType::LatinAlphabet, "good"),
+            (CharType::Kanji, "天気"),
+        ]
+    );
+
+    // 書記素クラスタの途中では分けない
+    let s = "か\u{3099}き";
+    assert_eq!(CharType::runs(s), vec![(CharType::Hiragana, 0..s.len())]);
+}
+
+#[test]
+fn test_char_type_runs_inherit() {
+    // 長音符や踊り字は直前の文字種に合わせる
+    let s = "あーゝカーヽ";
+    let runs = CharType::runs(s);
+    assert_eq!(
+        runs.iter()
+            .map(|(t, r)| (*t, &s[r.clone()]))
+            .collect::<Vec<_>>(),
+        vec![
+            (CharType::Hiragana, "あーゝ"),
+            (CharType::Katakana, "カーヽ")
+        ]
+    );
+}
+
+#[test]
+fn test_parse_number_kanji() {
+    for (i, c) in "一二三四五六七八九".chars().enumerate() {
+        assert_eq!(parse_number_kanji(&c.to_string()).unwrap(), i + 1);
+    }
+
+    assert_eq!(parse_number_kanji("十").unwrap(), 10);
+    assert_eq!(parse_number_kanji("十五").unwrap(), 15);
+    assert_eq!(parse_number_kanji("二十").unwrap(), 20);
+    assert_eq!(parse_number_kanji("九十九").unwrap(), 99);
+    assert_eq!(parse_number_kanji("百").unwrap(), 100);
+
+    // 算用数字
+    assert_eq!(parse_number_kanji("３").unwrap(), 3);
+    assert_eq!(parse_number_kanji("12").unwrap(), 12);
+
+    assert!(parse_number_kanji("十十").is_err());
+    assert!(parse_number_kanji("百一").is_err());
+    assert!(parse_number_kanji("二二").is_err());
+}
+
+#[test]
+fn test_decode_shift_jis_error_offset() {
+    // "あい" (82 A0 82 A2) の後ろに不正なバイト
+    let mut bytes = vec![0x82, 0xA0, 0x82, 0xA2, 0xFF, b'a'];
+    let message = format!("{:?}", decode_shift_jis(&bytes).unwrap_err());
+    assert!(
+        message.contains("Invalid Shift_JIS sequence at byte 4: [FF]"),
+        "{}",
+        message
+    );
+
+    // 2 バイト文字の 2 バイト目が不正
+    bytes[4] = 0x82;
+    bytes[5] = 0x7F;
+    let message = format!("{:?}", decode_shift_jis(&bytes).unwrap_err());
+    assert!(
+        message.contains("Invalid Shift_JIS sequence at byte 4:"),
+        "{}",
+        message
+    );
+
+    assert_eq!(decode_shift_jis(&bytes[..4]).unwrap(), "あい");
+}
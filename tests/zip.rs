@@ -0,0 +1,147 @@
+#![cfg(feature = "zip")]
+
+use std::{
+    io::{Cursor, Write},
+    path::Path,
+};
+
+use aozorabunko_json::{
+    ruby_txt::{parser::parse_ruby_txt, renderer::render_ruby_txt, tokenizer::tokenize_ruby_txt},
+    utility::zip::{find_txt_entry, select_txt, select_txt_index, ZipReader},
+};
+
+#[test]
+fn test_open_error_diagnosis() {
+    let html = b"<!DOCTYPE html><html></html>".to_vec();
+    let err = ZipReader::new(Cursor::new(html)).err().unwrap();
+    let message = format!("{:?}", err);
+    assert!(message.contains("size: 28 bytes"));
+    assert!(message.contains("[3C, 21, 44, 4F]"));
+    assert!(message.contains("looks like HTML"));
+
+    let err = ZipReader::open(Path::new("not/found.zip")).err().unwrap();
+    assert!(format!("{:?}", err).contains("File not found"));
+}
+
+#[test]
+fn test_select_txt() {
+    // .txt が 1 つだけ
+    assert_eq!(select_txt(&["a.txt", "a.png"]).unwrap(), "a.txt");
+
+    // ruby を含むものを優先する
+    assert_eq!(
+        select_txt(&["a_ocr.txt", "a_ruby_1.txt"]).unwrap(),
+        "a_ruby_1.txt"
+    );
+
+    // ruby を含むものが複数
+    assert!(select_txt(&["a_ruby_1.txt", "a_ruby_2.txt"]).is_err());
+
+    // ruby を含むものがなく .txt が複数
+    assert!(select_txt(&["a.txt", "b.txt"]).is_err());
+
+    // .txt がない
+    assert!(select_txt(&["a.png"]).is_err());
+}
+
+// 空のファイルを並べた zip
+fn zip_of(names: &[&str]) -> ZipReader<Cursor<Vec<u8>>> {
+    let entries: Vec<_> = names.iter().map(|name| (*name, &b""[..])).collect();
+    zip_with(&entries)
+}
+
+fn zip_with(entries: &[(&str, &[u8])]) -> ZipReader<Cursor<Vec<u8>>> {
+    let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    for (name, data) in entries {
+        writer
+            .start_file(*name, zip::write::FileOptions::default())
+            .unwrap();
+        writer.write_all(data).unwrap();
+    }
+    let cursor = writer.finish().unwrap();
+    ZipReader::new(Cursor::new(cursor.into_inner())).unwrap()
+}
+
+#[test]
+fn test_select_txt_index() {
+    let names = ["a.png", "A_RUBY_1.TXT", "a_ocr.txt"];
+    assert_eq!(select_txt_index(&names, true).unwrap(), 1);
+
+    // ruby を優先しなければ .txt が複数あるのはエラー
+    assert!(select_txt_index(&names, false).is_err());
+
+    assert_eq!(select_txt_index(&["a.png", "a.txt"], false).unwrap(), 1);
+    assert!(select_txt_index(&["a.png"], true).is_err());
+}
+
+#[test]
+fn test_find_txt_entry() {
+    // 位置は名前順ではなく zip の中の順
+    let mut zip = zip_of(&["a.png", "A_RUBY_1.TXT", "a_ocr.txt"]);
+    assert_eq!(find_txt_entry(&mut zip, true).unwrap(), (1, true));
+    assert_eq!(zip.get_by_index(1).unwrap().name(), "A_RUBY_1.TXT");
+
+    // ruby を優先しなければ .txt が複数あるのはエラー
+    assert!(find_txt_entry(&mut zip, false).is_err());
+
+    // .txt が 1 つだけなら選んだことにはならない
+    let mut zip = zip_of(&["b.txt", "a.png"]);
+    assert_eq!(find_txt_entry(&mut zip, true).unwrap(), (0, false));
+    assert_eq!(find_txt_entry(&mut zip, false).unwrap(), (0, false));
+
+    let mut zip = zip_of(&["a_ruby_1.txt", "a_ruby_2.txt"]);
+    assert!(find_txt_entry(&mut zip, true).is_err());
+
+    let mut zip = zip_of(&["a.png"]);
+    assert!(find_txt_entry(&mut zip, true).is_err());
+}
+
+#[test]
+fn test_get_txt() {
+    let mut zip = zip_of(&["a.png", "A_RUBY_1.TXT", "a_ocr.txt"]);
+    assert_eq!(
+        zip.sorted_file_names(),
+        vec!["A_RUBY_1.TXT", "a.png", "a_ocr.txt"]
+    );
+    assert_eq!(zip.get_txt().unwrap().name(), "A_RUBY_1.TXT");
+
+    let mut zip = zip_of(&["a.png"]);
+    assert!(zip.get_txt().is_err());
+}
+
+// main.rs と同じく zip から .txt を選び、Shift_JIS として読んでレンダリングまで通す
+#[test]
+fn test_pipeline_from_zip() -> anyhow::Result<()> {
+    let txt = "作品名\r\n著者名\r\n\r\n本文《ほんぶん》です\r\n\r\n底本：「底本」出版社\r\n";
+    let (sjis, _, unmappable) = encoding_rs::SHIFT_JIS.encode(txt);
+    assert!(!unmappable);
+
+    let mut zip = zip_with(&[("fig1_1.png", b""), ("sample_ruby_1.txt", &sjis)]);
+    let txt = zip.get_txt()?.as_shift_jis_string()?;
+
+    let tokens = tokenize_ruby_txt(&txt)?;
+    let parsed = parse_ruby_txt(&tokens)?;
+    let rendered = serde_json::to_value(render_ruby_txt(&parsed)?)?;
+
+    assert_eq!(
+        rendered["header"][0]["components"],
+        serde_json::json!([{ "type": "string", "value": "作品名" }])
+    );
+    assert_eq!(
+        rendered["body"][0]["components"],
+        serde_json::json!([
+            {
+                "type": "ruby",
+                "ruby": [{ "type": "string", "value": "ほんぶん" }],
+                "children": [{ "type": "string", "value": "本文" }],
+            },
+            { "type": "string", "value": "です" },
+        ])
+    );
+    assert_eq!(
+        rendered["footer"][0]["components"],
+        serde_json::json!([{ "type": "string", "value": "底本：「底本」出版社" }])
+    );
+
+    Ok(())
+}